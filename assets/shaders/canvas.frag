@@ -0,0 +1,18 @@
+#version 460 core
+
+in vec2 vUv;
+
+out vec4 outColor;
+
+uniform vec3 Color;
+uniform bool UseTexture;
+uniform sampler2D CanvasTexture;
+
+void main()
+{
+    vec3 color = Color;
+    if (UseTexture) {
+        color *= texture(CanvasTexture, vUv).rgb;
+    }
+    outColor = vec4(color, 1.0);
+}
@@ -0,0 +1,12 @@
+#version 460 core
+
+layout (location = 0) in vec2 Position;
+
+out vec2 vUv;
+
+uniform vec2 Tiling;
+
+void main() {
+  vUv = (Position * 0.5 + 0.5) * Tiling;
+  gl_Position = vec4(Position, 0.0, 1.0);
+}
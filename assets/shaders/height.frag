@@ -0,0 +1,22 @@
+#version 460 core
+
+in vec4 vColor;
+in vec2 vUv;
+in float vLayer;
+
+out vec4 outHeight;
+
+uniform sampler2DArray BrushTextures;
+uniform float Height;
+
+void main()
+{
+    float mask = 1.0;
+    if (vLayer >= 0.0) {
+        mask = texture(BrushTextures, vec3(vUv, vLayer)).r;
+    }
+    // Paint is deposited highest in the middle across the stroke width.
+    float x = vUv.y * 2.0 - 1.0;
+    float profile = sqrt(max(1.0 - x * x, 0.0));
+    outHeight = vec4(Height * profile * mask * vColor.a, 0.0, 0.0, 0.0);
+}
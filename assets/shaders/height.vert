@@ -0,0 +1,19 @@
+#version 460 core
+
+layout (location = 0) in vec2 Position;
+layout (location = 1) in vec4 Color;
+layout (location = 2) in vec2 Uv;
+layout (location = 3) in float Layer;
+
+out vec4 vColor;
+out vec2 vUv;
+out float vLayer;
+
+uniform mat4 ViewProjection;
+
+void main() {
+  vColor = Color;
+  vUv = Uv;
+  vLayer = Layer;
+  gl_Position = ViewProjection * vec4(Position, 0.0, 1.0);
+}
@@ -0,0 +1,36 @@
+#version 460 core
+
+out vec4 outColor;
+
+uniform sampler2D Pigments;
+uniform vec3 K[4];
+uniform vec3 S[4];
+uniform bool EncodeSrgb;
+
+vec3 linearToSrgb(vec3 c)
+{
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, c));
+}
+
+void main()
+{
+    // Concentrations are accumulated premultiplied by coverage, so divide by the sum to get the mix.
+    vec4 p = texelFetch(Pigments, ivec2(gl_FragCoord.xy), 0);
+    float coverage = p.x + p.y + p.z + p.w;
+    if (coverage <= 0.0) {
+        outColor = vec4(0.0);
+        return;
+    }
+    vec4 c = p / coverage;
+
+    vec3 k = K[0] * c.x + K[1] * c.y + K[2] * c.z + K[3] * c.w;
+    vec3 s = S[0] * c.x + S[1] * c.y + S[2] * c.z + S[3] * c.w;
+    vec3 ks = k / max(s, vec3(1e-6));
+    vec3 r = clamp(1.0 + ks - sqrt(ks * ks + 2.0 * ks), 0.0, 1.0);
+    if (EncodeSrgb) {
+        r = linearToSrgb(r);
+    }
+
+    float alpha = min(coverage, 1.0);
+    outColor = vec4(r * alpha, alpha);
+}
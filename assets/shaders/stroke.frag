@@ -1,10 +1,28 @@
 #version 460 core
 
 in vec4 vColor;
+in vec2 vUv;
+in float vLayer;
+in vec4 vPigment;
 
-out vec4 outColor;
+layout (location = 0, index = 0) out vec4 outColor;
+layout (location = 0, index = 1) out vec4 outCoverage;
+
+uniform sampler2DArray BrushTextures;
+uniform bool UsePigment;
 
 void main()
 {
-    outColor = vColor;
+    float mask = 1.0;
+    if (vLayer >= 0.0) {
+        mask = texture(BrushTextures, vec3(vUv, vLayer)).r;
+    }
+    float alpha = vColor.a * mask;
+    if (UsePigment) {
+        // Pigment concentrations sum to 1, so their sum after scaling is the coverage.
+        outColor = vPigment * alpha;
+    } else {
+        outColor = vec4(vColor.rgb * alpha, alpha);
+    }
+    outCoverage = vec4(alpha);
 }
@@ -2,12 +2,21 @@
 
 layout (location = 0) in vec2 Position;
 layout (location = 1) in vec4 Color;
+layout (location = 2) in vec2 Uv;
+layout (location = 3) in float Layer;
+layout (location = 4) in vec4 Pigment;
 
 out vec4 vColor;
+out vec2 vUv;
+out float vLayer;
+out vec4 vPigment;
 
 uniform mat4 ViewProjection;
 
 void main() {
   vColor = Color;
+  vUv = Uv;
+  vLayer = Layer;
+  vPigment = Pigment;
   gl_Position = ViewProjection * vec4(Position, 0.0, 1.0);
 }
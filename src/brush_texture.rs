@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::config::BrushConfig;
+use crate::render_gl::TextureArray;
+use crate::resources::Resources;
+
+pub const DEFAULT_BRUSH_TEXTURES: [&str; 3] = [
+    "brushes/bristle0.png",
+    "brushes/bristle1.png",
+    "brushes/bristle2.png",
+];
+
+// Size of each layer of the texture array. The x axis runs along the stroke.
+const BRUSH_TEXTURE_WIDTH: u32 = 256;
+const BRUSH_TEXTURE_HEIGHT: u32 = 64;
+
+pub fn texture_count(brush: &BrushConfig) -> usize {
+    if !brush.textured {
+        0
+    } else if brush.textures.is_empty() {
+        DEFAULT_BRUSH_TEXTURES.len()
+    } else {
+        brush.textures.len()
+    }
+}
+
+// Load as a mask where white receives paint. Alpha, if present, is multiplied in.
+fn to_mask(image: DynamicImage) -> Vec<u8> {
+    image
+        .resize_exact(
+            BRUSH_TEXTURE_WIDTH,
+            BRUSH_TEXTURE_HEIGHT,
+            FilterType::Triangle,
+        )
+        .to_luma_alpha()
+        .pixels()
+        .map(|p| ((p[0] as u32 * p[1] as u32) / 255) as u8)
+        .collect()
+}
+
+pub fn load_brush_textures(brush: &BrushConfig, res: &Resources) -> Result<Option<TextureArray>> {
+    if !brush.textured {
+        return Ok(None);
+    }
+
+    let images = if brush.textures.is_empty() {
+        DEFAULT_BRUSH_TEXTURES
+            .iter()
+            .map(|name| {
+                res.load_image(name)
+                    .with_context(|| format!("failed to load brush texture: {}", name))
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        brush
+            .textures
+            .iter()
+            .map(|path| {
+                image::open(path)
+                    .with_context(|| format!("failed to load brush texture: {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let layers = images.len();
+    let data = images.into_iter().flat_map(to_mask).collect::<Vec<_>>();
+
+    let texture = TextureArray::new();
+    texture.bind();
+    texture.r8_data(
+        BRUSH_TEXTURE_WIDTH as i32,
+        BRUSH_TEXTURE_HEIGHT as i32,
+        layers as i32,
+        &data,
+    );
+    texture.unbind();
+
+    Ok(Some(texture))
+}
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use c_str_macro::c_str;
+use image::GenericImageView;
+use na::{Vector2, Vector3};
+use nalgebra as na;
+
+use crate::color::srgb_to_linear;
+use crate::config::{ColorSpace, RenderConfig};
+use crate::render_gl::{self, buffer};
+use crate::resources::Resources;
+
+// Background under the strokes: a solid color, or a paper or canvas texture tinted by it.
+pub struct Canvas {
+    program: render_gl::Program,
+    _vbo: buffer::ArrayBuffer,
+    vao: buffer::VertexArray,
+    texture: Option<render_gl::Texture>,
+}
+
+impl Canvas {
+    pub fn new(
+        width: i32,
+        height: i32,
+        config: &RenderConfig,
+        res: &Resources,
+    ) -> Result<Option<Self>> {
+        if config.background.is_none() && config.canvas_texture.is_none() {
+            return Ok(None);
+        }
+
+        let linear = config.color_space == ColorSpace::Linear;
+
+        let program = render_gl::Program::from_res(res, "shaders/canvas")?;
+
+        let vertices: Vec<Vector2<f32>> = vec![
+            Vector2::new(-1.0, -1.0),
+            Vector2::new(1.0, -1.0),
+            Vector2::new(-1.0, 1.0),
+            Vector2::new(-1.0, 1.0),
+            Vector2::new(1.0, -1.0),
+            Vector2::new(1.0, 1.0),
+        ];
+
+        let vbo = buffer::ArrayBuffer::new();
+        vbo.bind();
+        vbo.static_draw_data(&vertices);
+        vbo.unbind();
+
+        let vao = buffer::VertexArray::new();
+        vao.bind();
+        vbo.bind();
+        unsafe {
+            gl::EnableVertexAttribArray(0);
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+        }
+        vbo.unbind();
+        vao.unbind();
+
+        let color = config.background.unwrap_or([255, 255, 255]);
+        let color = Vector3::new(color[0], color[1], color[2]).map(|c| {
+            let c = c as f32 / 255.0;
+            if linear {
+                srgb_to_linear(c)
+            } else {
+                c
+            }
+        });
+
+        let (texture, tiling) = if let Some(path) = &config.canvas_texture {
+            let image = image::open(path)
+                .with_context(|| format!("failed to load canvas texture: {}", path.display()))?;
+            let (w, h) = image.dimensions();
+            let texture = render_gl::Texture::new();
+            texture.bind();
+            texture.rgb8_data(w as i32, h as i32, &image.to_rgb().into_raw(), linear);
+            texture.unbind();
+            let tiling = Vector2::new(
+                width as f32 / (w as f32 * config.canvas_texture_scale),
+                height as f32 / (h as f32 * config.canvas_texture_scale),
+            );
+            (Some(texture), tiling)
+        } else {
+            (None, Vector2::new(1.0, 1.0))
+        };
+
+        program.set_used();
+        unsafe {
+            gl::Uniform3f(
+                gl::GetUniformLocation(program.id(), c_str!("Color").as_ptr()),
+                color.x,
+                color.y,
+                color.z,
+            );
+            gl::Uniform1i(
+                gl::GetUniformLocation(program.id(), c_str!("UseTexture").as_ptr()),
+                texture.is_some() as i32,
+            );
+            gl::Uniform1i(
+                gl::GetUniformLocation(program.id(), c_str!("CanvasTexture").as_ptr()),
+                1,
+            );
+            gl::Uniform2f(
+                gl::GetUniformLocation(program.id(), c_str!("Tiling").as_ptr()),
+                tiling.x,
+                tiling.y,
+            );
+        }
+
+        Ok(Some(Self {
+            program,
+            _vbo: vbo,
+            vao,
+            texture,
+        }))
+    }
+
+    // Alpha is not written, so the framebuffer alpha stays the stroke coverage.
+    pub fn draw(&self) {
+        self.program.set_used();
+        if let Some(texture) = &self.texture {
+            unsafe {
+                gl::ActiveTexture(gl::TEXTURE1);
+            }
+            texture.bind();
+        }
+        self.vao.bind();
+        unsafe {
+            gl::ColorMask(gl::TRUE, gl::TRUE, gl::TRUE, gl::FALSE);
+            gl::DrawArrays(gl::TRIANGLES, 0, 6);
+            gl::ColorMask(gl::TRUE, gl::TRUE, gl::TRUE, gl::TRUE);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use na::{Matrix3, Vector3};
+use nalgebra as na;
+
+// Conversion between sRGB and linear light. Both are in 0.0 to 1.0.
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// 8-bit sRGB to CIE L*a*b* with the D65 white point.
+pub fn srgb_to_lab(c: &Vector3<u8>) -> Vector3<f32> {
+    let c = c.map(|c| srgb_to_linear(c as f32 / 255.0));
+    let xyz = Matrix3::new(
+        0.4124, 0.3576, 0.1805, 0.2126, 0.7152, 0.0722, 0.0193, 0.1192, 0.9505,
+    ) * c;
+    let white = Vector3::new(0.95047, 1.0, 1.08883);
+    let f = xyz.component_div(&white).map(|t| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    });
+    Vector3::new(116.0 * f.y - 16.0, 500.0 * (f.x - f.y), 200.0 * (f.y - f.z))
+}
+
+pub fn lab_to_srgb(lab: &Vector3<f32>) -> Vector3<u8> {
+    let fy = (lab.x + 16.0) / 116.0;
+    let f = Vector3::new(fy + lab.y / 500.0, fy, fy - lab.z / 200.0);
+    let white = Vector3::new(0.95047, 1.0, 1.08883);
+    let xyz = f
+        .map(|f| {
+            if f > 6.0 / 29.0 {
+                f * f * f
+            } else {
+                (116.0 * f - 16.0) * 27.0 / 24389.0
+            }
+        })
+        .component_mul(&white);
+    let c = Matrix3::new(
+        3.2406, -1.5372, -0.4986, -0.9689, 1.8758, 0.0415, 0.0557, -0.2040, 1.0570,
+    ) * xyz;
+    c.map(|c| (linear_to_srgb(c) * 255.0).round() as u8)
+}
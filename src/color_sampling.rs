@@ -0,0 +1,125 @@
+use na::{Point2, Vector3};
+use nalgebra as na;
+use rand::prelude::*;
+use rand_distr::Normal;
+
+use crate::color::{lab_to_srgb, linear_to_srgb, srgb_to_lab, srgb_to_linear};
+use crate::config::{BrushConfig, ColorSampling};
+use crate::maps::Maps;
+
+// Thick strokes are subsampled to about this many pixels per side instead of checking all of them.
+const FOOTPRINT_SAMPLES_PER_THICKNESS: f32 = 8.0;
+
+// The mean is taken in linear light.
+fn mean(colors: &[Vector3<u8>], indices: &[usize]) -> Option<Vector3<u8>> {
+    if indices.is_empty() {
+        return None;
+    }
+    let sum = indices
+        .iter()
+        .map(|&i| colors[i].map(|c| srgb_to_linear(c as f32 / 255.0)))
+        .fold(Vector3::zeros(), |a, b| a + b);
+    let mean = sum / indices.len() as f32;
+    Some(mean.map(|c| (linear_to_srgb(c) * 255.0).round() as u8))
+}
+
+// Per-channel median
+fn median(colors: &[Vector3<u8>], indices: &[usize]) -> Option<Vector3<u8>> {
+    if indices.is_empty() {
+        return None;
+    }
+    let channel = |k: usize| {
+        let mut values = indices.iter().map(|&i| colors[i][k]).collect::<Vec<_>>();
+        values.sort_unstable();
+        values[values.len() / 2]
+    };
+    Some(Vector3::new(channel(0), channel(1), channel(2)))
+}
+
+fn distance_to_segment(p: &Point2<f32>, a: &Point2<f32>, b: &Point2<f32>) -> f32 {
+    let ab = b - a;
+    let t = if ab.norm_squared() > 0.0 {
+        ((p - a).dot(&ab) / ab.norm_squared()).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    na::distance(p, &(a + ab * t))
+}
+
+// Pixels within half the thickness of the polyline through the hopping points
+fn footprint(points: &[Point2<f32>], thickness: f32, maps: &Maps) -> Vec<usize> {
+    let radius = thickness / 2.0;
+    let step = (thickness / FOOTPRINT_SAMPLES_PER_THICKNESS).max(1.0);
+    let (min, max) = points.iter().fold(
+        (
+            Point2::new(f32::MAX, f32::MAX),
+            Point2::new(f32::MIN, f32::MIN),
+        ),
+        |(min, max), p| (min.inf(p), max.sup(p)),
+    );
+
+    let mut indices = vec![];
+    let mut y = (min.y - radius).max(0.0);
+    while y <= (max.y + radius).min(maps.height as f32 - 1.0) {
+        let mut x = (min.x - radius).max(0.0);
+        while x <= (max.x + radius).min(maps.width as f32 - 1.0) {
+            let p = Point2::new(x, y);
+            let inside = if points.len() == 1 {
+                na::distance(&p, &points[0]) <= radius
+            } else {
+                points
+                    .iter()
+                    .zip(points.iter().skip(1))
+                    .any(|(a, b)| distance_to_segment(&p, a, b) <= radius)
+            };
+            if inside {
+                if let Some(i) = maps.index(&p).filter(|&i| maps.is_paintable(i)) {
+                    indices.push(i);
+                }
+            }
+            x += step;
+        }
+        y += step;
+    }
+    indices
+}
+
+pub fn sample_color(
+    index: usize,
+    points: &[Point2<f32>],
+    thickness: f32,
+    maps: &Maps,
+    brush: &BrushConfig,
+) -> Vector3<u8> {
+    let colors = &maps.colors;
+    // Transparent pixels of the color map are not painted, so their colors are not sampled either.
+    // If nothing paintable is left, the seed color is used.
+    let path = || {
+        points
+            .iter()
+            .filter_map(|p| maps.index(p))
+            .filter(|&i| maps.is_paintable(i))
+            .collect::<Vec<_>>()
+    };
+    let color = match brush.color_sampling {
+        ColorSampling::Seed => None,
+        ColorSampling::PathMean => mean(colors, &path()),
+        ColorSampling::PathMedian => median(colors, &path()),
+        ColorSampling::FootprintMean => mean(colors, &footprint(points, thickness, maps)),
+    }
+    .unwrap_or(colors[index]);
+
+    if brush.color_jitter > 0.0 {
+        let normal = Normal::new(0.0, brush.color_jitter).unwrap();
+        let mut rng = thread_rng();
+        let lab = srgb_to_lab(&color)
+            + Vector3::new(
+                normal.sample(&mut rng),
+                normal.sample(&mut rng),
+                normal.sample(&mut rng),
+            );
+        lab_to_srgb(&Vector3::new(lab.x.clamp(0.0, 100.0), lab.y, lab.z))
+    } else {
+        color
+    }
+}
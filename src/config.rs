@@ -0,0 +1,597 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::thickness_profile::TAPER_MAX;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("failed to parse toml config: {0}")]
+    ParseToml(#[from] toml::de::Error),
+    #[error("failed to write toml config: {0}")]
+    SerializeToml(#[from] toml::ser::Error),
+    #[error("failed to parse yaml config: {0}")]
+    ParseYaml(#[from] serde_yaml::Error),
+    #[error("unknown config file extension: {path}")]
+    UnknownExtension { path: PathBuf },
+    #[error("`{name}` is not specified in the config file nor by the command line")]
+    MissingValue { name: &'static str },
+    #[error("{count} pigments are specified but at most {max} can be mixed")]
+    TooManyPigments { count: usize, max: usize },
+    #[error("no pigments are specified for the kubelka_munk compositor")]
+    NoPigments,
+    #[error("the path is not valid UTF-8: {path}")]
+    NonUtf8Path { path: PathBuf },
+    #[error("`{name}` must be between {min} and {max} but is {value}")]
+    OutOfRange {
+        name: &'static str,
+        value: f64,
+        min: f64,
+        max: f64,
+    },
+}
+
+// NaN is rejected as well, since it fails both comparisons.
+fn check_range(name: &'static str, value: f64, min: f64, max: f64) -> Result<(), ConfigError> {
+    if min <= value && value <= max {
+        Ok(())
+    } else {
+        Err(ConfigError::OutOfRange {
+            name,
+            value,
+            min,
+            max,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RunConfig {
+    pub input: InputConfig,
+    pub brush: BrushConfig,
+    pub seeding: SeedingConfig,
+    pub palette: PaletteConfig,
+    pub render: RenderConfig,
+    pub ga: GaConfig,
+    pub output: OutputConfig,
+    // Brush settings and stroke counts per label map region
+    pub regions: Vec<RegionConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct InputConfig {
+    pub color_map: Option<PathBuf>,
+    pub dir_map: Option<PathBuf>,
+    pub importance_map: Option<PathBuf>,
+    // Derived from the color map gradient if not given.
+    pub edge_map: Option<PathBuf>,
+    // Image painted with one color per region. Strokes do not grow out of their seed's region.
+    pub label_map: Option<PathBuf>,
+    // Resample maps whose size differs from the color map instead of failing.
+    pub resize_to_color_map: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BrushConfig {
+    pub stroke_num: u32,
+    pub stroke_thickness: f32,
+    // Mean length of the thinning at the start and end, as a fraction of the stroke. At most 0.5.
+    pub taper_in: f32,
+    pub taper_out: f32,
+    pub taper_variance: f32,
+    // Width at the tip, as a fraction of the stroke thickness
+    pub taper_tip_width: f32,
+    // Number of control points of the pressure curve and their variance
+    pub pressure_points: usize,
+    pub pressure_variance: f32,
+    // Bristle textures. If empty, the textures in assets/brushes are used.
+    pub textured: bool,
+    pub textures: Vec<PathBuf>,
+    // Distribution of stroke opacity
+    pub opacity_mean: f32,
+    pub opacity_variance: f32,
+    pub opacity_min: f32,
+    pub color_sampling: ColorSampling,
+    // Standard deviation of the color jitter in L*a*b*. 0 disables it.
+    pub color_jitter: f32,
+    // Strokes do not grow across pixels whose edge strength exceeds this. None ignores edges.
+    pub edge_threshold: Option<f32>,
+}
+
+impl Default for BrushConfig {
+    fn default() -> Self {
+        Self {
+            stroke_num: 10000,
+            stroke_thickness: 1.0,
+            taper_in: 0.15,
+            taper_out: 0.3,
+            taper_variance: 0.1,
+            taper_tip_width: 0.2,
+            pressure_points: 4,
+            pressure_variance: 0.15,
+            textured: false,
+            textures: vec![],
+            opacity_mean: 1.0,
+            opacity_variance: 0.0,
+            opacity_min: 0.2,
+            color_sampling: ColorSampling::Seed,
+            color_jitter: 0.0,
+            edge_threshold: None,
+        }
+    }
+}
+
+impl BrushConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        check_range(
+            "brush.taper_in",
+            self.taper_in as f64,
+            0.0,
+            TAPER_MAX as f64,
+        )?;
+        check_range(
+            "brush.taper_out",
+            self.taper_out as f64,
+            0.0,
+            TAPER_MAX as f64,
+        )?;
+        check_range(
+            "brush.taper_variance",
+            self.taper_variance as f64,
+            0.0,
+            f64::INFINITY,
+        )?;
+        check_range(
+            "brush.taper_tip_width",
+            self.taper_tip_width as f64,
+            0.0,
+            1.0,
+        )?;
+        check_range(
+            "brush.pressure_variance",
+            self.pressure_variance as f64,
+            0.0,
+            f64::INFINITY,
+        )?;
+        check_range("brush.opacity_mean", self.opacity_mean as f64, 0.0, 1.0)?;
+        check_range(
+            "brush.opacity_variance",
+            self.opacity_variance as f64,
+            0.0,
+            f64::INFINITY,
+        )?;
+        check_range("brush.opacity_min", self.opacity_min as f64, 0.0, 1.0)?;
+        Ok(())
+    }
+}
+
+// How a stroke picks its color. Seed uses the seed pixel, PathMean and PathMedian the mean and
+// median of the pixels under the hopping points, and FootprintMean the mean of the covered pixels.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSampling {
+    Seed,
+    PathMean,
+    PathMedian,
+    FootprintMean,
+}
+
+// How stroke seeds are chosen.
+// Weighted picks them independently in proportion to importance. PoissonDisk picks points that
+// are not too close to each other, with a shorter spacing where importance is high.
+// JitteredGrid gives each grid cell a number of points proportional to its importance and
+// jitters them inside the cell. ErrorDriven picks in proportion to the color error times the
+// importance, and behaves like Weighted while the error is unknown.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedingStrategy {
+    Weighted,
+    PoissonDisk,
+    JitteredGrid,
+    ErrorDriven,
+}
+
+impl FromStr for SeedingStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "weighted" => Ok(SeedingStrategy::Weighted),
+            "poisson-disk" => Ok(SeedingStrategy::PoissonDisk),
+            "jittered-grid" => Ok(SeedingStrategy::JitteredGrid),
+            "error-driven" => Ok(SeedingStrategy::ErrorDriven),
+            _ => Err(anyhow!("unknown seeding strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SeedingConfig {
+    pub strategy: SeedingStrategy,
+    // Fraction of seeds chosen uniformly, ignoring importance
+    pub uniform_ratio: f32,
+    // poisson_disk spacing in pixels at importance 0. Derived from the count and area if None.
+    pub min_distance: Option<f32>,
+    // Previous result used by error_driven to seed the first individuals
+    pub error_reference: Option<PathBuf>,
+}
+
+impl Default for SeedingConfig {
+    fn default() -> Self {
+        Self {
+            strategy: SeedingStrategy::Weighted,
+            uniform_ratio: 0.05,
+            min_distance: None,
+            error_reference: None,
+        }
+    }
+}
+
+// Brush for the region of the label map with the color `label`. Unset fields use `brush`.
+// With stroke_num, that many strokes are placed inside the region apart from brush.stroke_num.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RegionConfig {
+    pub label: [u8; 3],
+    pub stroke_num: Option<u32>,
+    pub stroke_thickness: Option<f32>,
+    pub opacity_mean: Option<f32>,
+    pub opacity_variance: Option<f32>,
+    pub color_sampling: Option<ColorSampling>,
+    pub color_jitter: Option<f32>,
+    pub edge_threshold: Option<f32>,
+}
+
+impl RegionConfig {
+    pub fn brush(&self, base: &BrushConfig) -> BrushConfig {
+        BrushConfig {
+            stroke_num: self.stroke_num.unwrap_or(base.stroke_num),
+            stroke_thickness: self.stroke_thickness.unwrap_or(base.stroke_thickness),
+            opacity_mean: self.opacity_mean.unwrap_or(base.opacity_mean),
+            opacity_variance: self.opacity_variance.unwrap_or(base.opacity_variance),
+            color_sampling: self.color_sampling.unwrap_or(base.color_sampling),
+            color_jitter: self.color_jitter.unwrap_or(base.color_jitter),
+            edge_threshold: self.edge_threshold.or(base.edge_threshold),
+            ..base.clone()
+        }
+    }
+}
+
+// Restricts the paint colors: the colors in `file`, or `size` colors derived from the color map.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PaletteConfig {
+    pub file: Option<PathBuf>,
+    pub size: Option<usize>,
+    // k-means iterations and number of sampled pixels when derived from the color map
+    pub iterations: usize,
+    pub sample_num: usize,
+}
+
+impl Default for PaletteConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            size: None,
+            iterations: 20,
+            sample_num: 20000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SplineType {
+    Polyline,
+    CatmullRom,
+    Centripetal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinType {
+    Miter,
+    Bevel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CapType {
+    Butt,
+    Round,
+    Taper,
+}
+
+// Srgb composites the sRGB values as they are. Linear composites in linear light and outputs sRGB.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+// Over layers the stroke colors directly. KubelkaMunk mixes pigment concentrations, then converts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compositor {
+    Over,
+    KubelkaMunk,
+}
+
+// Kubelka-Munk absorption k and scattering s, given per RGB channel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pigment {
+    pub name: String,
+    pub k: [f32; 3],
+    pub s: [f32; 3],
+}
+
+impl Pigment {
+    fn new(name: &str, k: [f32; 3], s: [f32; 3]) -> Self {
+        Self {
+            name: name.to_string(),
+            k,
+            s,
+        }
+    }
+}
+
+fn default_pigments() -> Vec<Pigment> {
+    vec![
+        Pigment::new("titanium_white", [0.005, 0.005, 0.005], [1.0, 1.0, 1.0]),
+        Pigment::new("phthalo_blue", [3.0, 1.0, 0.1], [0.2, 0.3, 0.6]),
+        Pigment::new("quinacridone_magenta", [0.1, 3.0, 0.5], [0.6, 0.2, 0.4]),
+        Pigment::new("hansa_yellow", [0.05, 0.2, 4.0], [0.8, 0.7, 0.2]),
+    ]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RenderConfig {
+    pub spline: SplineType,
+    // Number of subdivisions per hopping point segment
+    pub subdivision: u32,
+    pub join: JoinType,
+    // A miter longer than this multiple of half the thickness becomes a bevel.
+    pub miter_limit: f32,
+    pub cap: CapType,
+    pub color_space: ColorSpace,
+    pub compositor: Compositor,
+    // Background color and paper or canvas texture. Without either, the background is transparent.
+    pub background: Option<[u8; 3]>,
+    pub canvas_texture: Option<PathBuf>,
+    pub canvas_texture_scale: f32,
+    // If true, areas not covered by strokes are allowed.
+    pub allow_uncovered: bool,
+    // Tables must come after plain values in toml, so these go last.
+    // Pigments mixed when the compositor is kubelka_munk. At most 4.
+    pub pigments: Vec<Pigment>,
+    pub impasto: ImpastoConfig,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            spline: SplineType::Centripetal,
+            subdivision: 4,
+            join: JoinType::Miter,
+            miter_limit: 4.0,
+            cap: CapType::Round,
+            color_space: ColorSpace::Srgb,
+            compositor: Compositor::Over,
+            background: None,
+            canvas_texture: None,
+            canvas_texture_scale: 1.0,
+            allow_uncovered: false,
+            pigments: default_pigments(),
+            impasto: ImpastoConfig::default(),
+        }
+    }
+}
+
+// Output of the height and normal maps of the paint relief
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ImpastoConfig {
+    pub enabled: bool,
+    // Paint height deposited by one stroke, in pixels
+    pub height: f32,
+    pub normal_strength: f32,
+    // If true, also save an image lit with the normal map.
+    pub lit: bool,
+    pub light_direction: [f32; 3],
+    pub ambient: f32,
+    pub specular: f32,
+    pub shininess: f32,
+}
+
+impl Default for ImpastoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            height: 1.0,
+            normal_strength: 2.0,
+            lit: true,
+            light_direction: [-1.0, 1.0, 1.5],
+            ambient: 0.35,
+            specular: 0.2,
+            shininess: 32.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GaConfig {
+    pub population_size: u32,
+    pub generation: usize,
+    pub d_value: i32,
+    pub mutation_probability: f64,
+    pub profile_mutation_probability: f64,
+    pub opacity_mutation_probability: f64,
+    pub palette_mutation_probability: f64,
+}
+
+impl Default for GaConfig {
+    fn default() -> Self {
+        Self {
+            population_size: 250,
+            generation: 100,
+            d_value: 50,
+            mutation_probability: 0.35,
+            profile_mutation_probability: 0.1,
+            opacity_mutation_probability: 0.1,
+            palette_mutation_probability: 0.05,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub output_path: Option<PathBuf>,
+    // If not given, the output has the size of the color map.
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub save_generation: Vec<usize>,
+    pub save_generation_step: Option<usize>,
+    pub save_sequence: Option<usize>,
+    pub window_height: u32,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            output_path: None,
+            width: None,
+            height: None,
+            save_generation: vec![],
+            save_generation_step: None,
+            save_sequence: None,
+            window_height: 1080,
+        }
+    }
+}
+
+impl RunConfig {
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(toml::from_str(&text)?),
+            Some("yaml") | Some("yml") => Ok(serde_yaml::from_str(&text)?),
+            _ => Err(ConfigError::UnknownExtension { path: path.into() }),
+        }
+    }
+
+    // Reject values that would make a distribution fail to build and panic in the middle of a run.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let ga = &self.ga;
+        check_range(
+            "ga.population_size",
+            ga.population_size as f64,
+            1.0,
+            f64::INFINITY,
+        )?;
+        check_range("ga.mutation_probability", ga.mutation_probability, 0.0, 1.0)?;
+        check_range(
+            "ga.profile_mutation_probability",
+            ga.profile_mutation_probability,
+            0.0,
+            1.0,
+        )?;
+        check_range(
+            "ga.opacity_mutation_probability",
+            ga.opacity_mutation_probability,
+            0.0,
+            1.0,
+        )?;
+        check_range(
+            "ga.palette_mutation_probability",
+            ga.palette_mutation_probability,
+            0.0,
+            1.0,
+        )?;
+        self.brush.validate()?;
+        // Regions may override the opacity, so check the brushes they resolve to as well.
+        for region in &self.regions {
+            region.brush(&self.brush).validate()?;
+        }
+        if let Some(size) = self.palette.size {
+            check_range("palette.size", size as f64, 1.0, f64::INFINITY)?;
+        }
+        check_range(
+            "palette.sample_num",
+            self.palette.sample_num as f64,
+            1.0,
+            f64::INFINITY,
+        )?;
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let text = toml::to_string_pretty(self)?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub fn color_map(&self) -> Result<&Path, ConfigError> {
+        self.input
+            .color_map
+            .as_deref()
+            .ok_or(ConfigError::MissingValue { name: "color_map" })
+    }
+
+    pub fn dir_map(&self) -> Result<&Path, ConfigError> {
+        self.input
+            .dir_map
+            .as_deref()
+            .ok_or(ConfigError::MissingValue { name: "dir_map" })
+    }
+
+    pub fn importance_map(&self) -> Result<&Path, ConfigError> {
+        self.input
+            .importance_map
+            .as_deref()
+            .ok_or(ConfigError::MissingValue {
+                name: "importance_map",
+            })
+    }
+
+    pub fn output_path(&self) -> Result<&Path, ConfigError> {
+        self.output
+            .output_path
+            .as_deref()
+            .ok_or(ConfigError::MissingValue {
+                name: "output_path",
+            })
+    }
+
+    // The renderer takes the output path as &str, so non-UTF-8 paths are rejected here.
+    pub fn output_path_str(&self) -> Result<&str, ConfigError> {
+        let path = self.output_path()?;
+        path.to_str()
+            .ok_or_else(|| ConfigError::NonUtf8Path { path: path.into() })
+    }
+
+    // Save the resolved config next to the output so the run can be reproduced.
+    pub fn resolved_config_path(&self) -> Result<PathBuf, ConfigError> {
+        let output_path = self.output_path()?;
+        let mut file_name = output_path
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_default();
+        file_name.push(".config.toml");
+        Ok(output_path.with_file_name(file_name))
+    }
+}
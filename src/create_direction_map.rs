@@ -1,18 +1,33 @@
-use image::{GenericImageView, RgbImage};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use image::GenericImageView;
 use na::{Vector2, Vector3};
 use nalgebra as na;
 use rayon::prelude::*;
 
-pub fn create_direction_map_from_normal(input: &str, output: &str) {
-    let normal_map = image::open(input).unwrap();
+use crate::direction_encoding::DirectionOutput;
+use crate::direction_field::{Degenerate, DirectionField, DirectionMethod, Smoothing};
+use crate::distance_transform::euclidean_distance_transform;
+use crate::guide_curves::GuideCurves;
+
+// Blend the directions from the guide curves with the automatically derived direction map `base`.
+// The whiter weight_map is, the more the curves win. Without it, base takes over `falloff` px away.
+#[derive(Debug, Clone)]
+pub struct CurveBlend {
+    pub base: PathBuf,
+    pub weight_map: Option<PathBuf>,
+    pub falloff: f32,
+}
+
+fn direction_field_from_normal(input: &Path) -> Result<DirectionField> {
+    let normal_map = image::open(input)?;
     let (width, height) = normal_map.dimensions();
-    let mut output_image = RgbImage::new(width, height);
-    output_image
-        .enumerate_pixels_mut()
-        .collect::<Vec<_>>()
-        .par_iter_mut()
-        .for_each(|(x, y, pixel)| {
-            let normal = normal_map.get_pixel(*x, *y);
+    let directions = (0..(width * height))
+        .into_par_iter()
+        .map(|index| {
+            let (x, y) = (index % width, index / width);
+            let normal = normal_map.get_pixel(x, y);
             let normal = Vector3::new(
                 normal.0[0] as f64 / 255.0,
                 normal.0[1] as f64 / 255.0,
@@ -22,47 +37,34 @@ pub fn create_direction_map_from_normal(input: &str, output: &str) {
             let view_dir = Vector3::<f64>::new(0.0, 0.0, 1.0);
             let dir = normal.normalize().cross(&view_dir);
             let dir = Vector2::new(dir.x, dir.y).normalize();
-            let dir = (dir + Vector2::<f64>::new(1.0, 1.0)) * 0.5;
-            pixel[0] = (255.0 * dir.x) as u8;
-            pixel[1] = (255.0 * dir.y) as u8;
-            pixel[2] = 0;
-        });
-    output_image.save(output).unwrap();
+            Vector2::new(dir.x as f32, dir.y as f32)
+        })
+        .collect();
+
+    Ok(DirectionField::new(width, height, directions))
 }
 
-pub fn create_direction_map_from_edge(input: &str, output: &str) {
-    let edge_map = image::open(input).unwrap();
+fn direction_field_from_edge(input: &Path) -> Result<DirectionField> {
+    let edge_map = image::open(input)?;
     let (width, height) = edge_map.dimensions();
 
-    let edge_points = edge_map
+    let is_edge = edge_map
         .to_luma()
-        .enumerate_pixels()
-        .filter(|(_, _, pixel)| pixel[0] >= 128)
-        .map(|(x, y, _)| (x, y))
+        .pixels()
+        .map(|pixel| pixel[0] >= 128)
         .collect::<Vec<_>>();
+    if !is_edge.iter().any(|&e| e) {
+        return Err(anyhow!("The edge map has no edge pixels."));
+    }
 
-    let distance_map = (0..(width * height))
-        .collect::<Vec<_>>()
-        .par_iter()
+    let transform = euclidean_distance_transform(&is_edge, width, height);
+    let distance_map = &transform.distances;
+
+    let directions = (0..(width * height))
+        .into_par_iter()
         .map(|index| {
             let (x, y) = (index % width, index / width);
-            edge_points
-                .iter()
-                .map(|(ex, ey)| {
-                    (x as f64 - *ex as f64).powf(2.0) + (y as f64 - *ey as f64).powf(2.0)
-                })
-                .min_by(|a, b| a.partial_cmp(b).unwrap())
-                .unwrap()
-        })
-        .collect::<Vec<_>>();
-
-    let mut output_image = RgbImage::new(width, height);
-    output_image
-        .enumerate_pixels_mut()
-        .collect::<Vec<_>>()
-        .par_iter_mut()
-        .for_each(|(x, y, pixel)| {
-            let index = (*x + *y * width) as usize;
+            let index = index as usize;
             let (width, height) = (width as usize, height as usize);
             let p = distance_map[index];
             let p_x_prev = if index % width == 0 {
@@ -88,12 +90,149 @@ pub fn create_direction_map_from_edge(input: &str, output: &str) {
             let dir = Vector2::new(
                 -((p_y_next + p) - (p + p_y_prev)),
                 (p_x_next + p) - (p + p_x_prev),
-            )
-            .normalize();
-            let dir = (dir + Vector2::<f64>::new(1.0, 1.0)) * 0.5;
-            pixel[0] = (255.0 * dir.x) as u8;
-            pixel[1] = (255.0 * dir.y) as u8;
-            pixel[2] = 0;
-        });
-    output_image.save(output).unwrap();
+            );
+            // Where the nearest edges balance out on both sides and the difference is 0, use the
+            // direction to the nearest edge point instead.
+            let dir = if dir.norm() > 0.0 {
+                dir.normalize()
+            } else if let Some((ex, ey)) = transform.features[index] {
+                let to_edge = Vector2::new(x as f64 - ex as f64, y as f64 - ey as f64);
+                Vector2::new(-to_edge.y, to_edge.x).normalize()
+            } else {
+                dir.normalize()
+            };
+            Vector2::new(dir.x as f32, dir.y as f32)
+        })
+        .collect();
+
+    Ok(DirectionField::new(width, height, directions))
+}
+
+// If no guide is given, default_guide is the color reference for bilateral.
+fn smooth_direction_field(
+    field: DirectionField,
+    smoothing: Option<&Smoothing>,
+    default_guide: Option<&Path>,
+) -> Result<DirectionField> {
+    let smoothing = match smoothing {
+        Some(smoothing) => smoothing,
+        None => return Ok(field),
+    };
+    let guide = match smoothing.guide.as_deref().or(default_guide) {
+        Some(path) => Some(image::open(path)?.to_rgb()),
+        None => None,
+    };
+    field.smooth(smoothing, guide.as_ref())
+}
+
+// Fill pixels without a direction, smooth, and save.
+fn finish_direction_field(
+    mut field: DirectionField,
+    output: &DirectionOutput,
+    degenerate: &Degenerate,
+    smoothing: Option<&Smoothing>,
+    default_guide: Option<&Path>,
+) -> Result<()> {
+    field.fill_degenerate(degenerate.fill, degenerate.default_angle);
+    let degenerate_count = field.confidence.iter().filter(|&&c| c <= 0.0).count();
+    if degenerate_count > 0 {
+        println!(">> Filled {} degenerate pixels", degenerate_count);
+    }
+    let field = smooth_direction_field(field, smoothing, default_guide)?;
+    if let Some(path) = &degenerate.confidence_output {
+        println!(">> Confidence file: {}", path.display());
+        field.save_confidence(path)?;
+    }
+    field.save(output)
+}
+
+pub fn create_direction_map_from_normal(
+    input: &Path,
+    output: &DirectionOutput,
+    degenerate: &Degenerate,
+    smoothing: Option<&Smoothing>,
+) -> Result<()> {
+    let field = direction_field_from_normal(input)?;
+    finish_direction_field(field, output, degenerate, smoothing, None)
+}
+
+pub fn create_direction_map_from_edge(
+    input: &Path,
+    output: &DirectionOutput,
+    degenerate: &Degenerate,
+    smoothing: Option<&Smoothing>,
+) -> Result<()> {
+    let field = direction_field_from_edge(input)?;
+    finish_direction_field(field, output, degenerate, smoothing, None)
+}
+
+pub fn create_direction_map_from_image(
+    input: &Path,
+    output: &DirectionOutput,
+    method: DirectionMethod,
+    radius: u32,
+    iterations: u32,
+    degenerate: &Degenerate,
+    smoothing: Option<&Smoothing>,
+) -> Result<()> {
+    let image = image::open(input)?;
+    let field = DirectionField::from_image(&image, method, radius, iterations);
+    finish_direction_field(field, output, degenerate, smoothing, Some(input))
+}
+
+// The output size follows the reference image or the base direction map to blend.
+pub fn create_direction_map_from_curves(
+    input: &Path,
+    output: &DirectionOutput,
+    reference: Option<&Path>,
+    blend: Option<&CurveBlend>,
+    degenerate: &Degenerate,
+    smoothing: Option<&Smoothing>,
+) -> Result<()> {
+    let curves = GuideCurves::load(input)?;
+    let base = match blend {
+        Some(blend) => Some(DirectionField::load(&blend.base)?),
+        None => None,
+    };
+    let (width, height) = match (reference, &base) {
+        (Some(path), _) => image::image_dimensions(path)?,
+        (None, Some(base)) => (base.width, base.height),
+        (None, None) => {
+            return Err(anyhow!(
+                "either a reference image or a base direction map is needed for the output size"
+            ))
+        }
+    };
+
+    // Where the curves give no direction the doubled-angle vector is 0, so the blend yields base.
+    let mut field = curves.direction_field(width, height)?;
+    if let (Some(blend), Some(base)) = (blend, &base) {
+        let weights = match &blend.weight_map {
+            Some(path) => {
+                let weight_map = image::open(path)?.to_luma();
+                if weight_map.dimensions() != (width, height) {
+                    return Err(anyhow!(
+                        "the weight map is {}x{} but the direction map is {}x{}",
+                        weight_map.width(),
+                        weight_map.height(),
+                        width,
+                        height
+                    ));
+                }
+                weight_map.pixels().map(|p| p[0] as f32 / 255.0).collect()
+            }
+            None => curves.falloff_weights(width, height, blend.falloff),
+        };
+        field = field.blend(base, &weights)?;
+    }
+    finish_direction_field(field, output, degenerate, smoothing, None)
+}
+
+pub fn smooth_direction_map(
+    input: &Path,
+    output: &DirectionOutput,
+    smoothing: &Smoothing,
+) -> Result<()> {
+    let field = DirectionField::load(input)?;
+    smooth_direction_field(field, Some(smoothing), None)?.save(output)
 }
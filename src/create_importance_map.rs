@@ -0,0 +1,296 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use delta_e::DE2000;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, Luma};
+use na::Vector3;
+use nalgebra as na;
+
+use crate::color::srgb_to_lab;
+use crate::direction_field::{gaussian_blur, gradient_magnitude};
+use crate::distance_transform::euclidean_distance_transform;
+use crate::maps::PAINTABLE_ALPHA;
+
+// Blur radius used by frequency-tuned saliency to drop fine noise and texture
+const SALIENCY_BLUR_RADIUS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportanceMethod {
+    // Magnitude of the luminance gradient
+    Gradient,
+    // Standard deviation of the luminance in a neighborhood
+    Contrast,
+    // Frequency-tuned saliency by Achanta et al. Larger for colors far from the image mean.
+    Saliency,
+    // Larger close to strong edges.
+    EdgeDistance,
+}
+
+impl FromStr for ImportanceMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gradient" => Ok(ImportanceMethod::Gradient),
+            "contrast" => Ok(ImportanceMethod::Contrast),
+            "saliency" => Ok(ImportanceMethod::Saliency),
+            "edge-distance" => Ok(ImportanceMethod::EdgeDistance),
+            _ => Err(anyhow!("unknown importance method: {}", s)),
+        }
+    }
+}
+
+// A method and its blend weight, such as "gradient" or "saliency:0.5".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImportanceTerm {
+    pub method: ImportanceMethod,
+    pub weight: f32,
+}
+
+impl FromStr for ImportanceTerm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let method = parts.next().unwrap().parse()?;
+        let weight: f32 = match parts.next() {
+            Some(weight) => weight
+                .parse()
+                .map_err(|_| anyhow!("invalid importance weight: {}", s))?,
+            None => 1.0,
+        };
+        if weight.is_nan() || weight < 0.0 {
+            return Err(anyhow!("importance weight must not be negative: {}", s));
+        }
+        Ok(Self { method, weight })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportanceOptions {
+    pub terms: Vec<ImportanceTerm>,
+    // Neighborhood radius for contrast
+    pub radius: u32,
+    // Gradient magnitude treated as an edge by edge-distance, relative to the maximum of 1.0
+    pub edge_threshold: f32,
+    // Distance in pixels over which edge-distance importance falls off
+    pub falloff: f32,
+    // Gamma applied after blending and normalizing. Above 1.0 favors the most important pixels.
+    pub gamma: f32,
+}
+
+// Scale so that the maximum is 1.0.
+fn normalize_max(values: Vec<f32>) -> Vec<f32> {
+    let max = values.iter().cloned().fold(0.0, f32::max);
+    if max > 0.0 {
+        values.iter().map(|v| v / max).collect()
+    } else {
+        values
+    }
+}
+
+// Stretch to 0.0 to 1.0.
+fn normalize_range(values: Vec<f32>) -> Vec<f32> {
+    let min = values.iter().cloned().fold(f32::MAX, f32::min);
+    let max = values.iter().cloned().fold(f32::MIN, f32::max);
+    if max > min {
+        values.iter().map(|v| (v - min) / (max - min)).collect()
+    } else {
+        vec![0.0; values.len()]
+    }
+}
+
+fn local_contrast(image: &DynamicImage, radius: u32) -> Vec<f32> {
+    let (width, height) = image.dimensions();
+    let luma = image
+        .to_luma()
+        .pixels()
+        .map(|p| p[0] as f32 / 255.0)
+        .collect::<Vec<_>>();
+    let squared = luma.iter().map(|l| l * l).collect::<Vec<_>>();
+    let sigma = (radius as f32 / 2.0).max(0.5);
+    let mean = gaussian_blur(&luma, width, height, radius, sigma);
+    let mean_squared = gaussian_blur(&squared, width, height, radius, sigma);
+    normalize_max(
+        mean.iter()
+            .zip(mean_squared.iter())
+            .map(|(m, m2)| (m2 - m * m).max(0.0).sqrt())
+            .collect(),
+    )
+}
+
+fn frequency_tuned_saliency(image: &DynamicImage) -> Vec<f32> {
+    let (width, height) = image.dimensions();
+    let lab = image
+        .to_rgb()
+        .pixels()
+        .map(|p| srgb_to_lab(&Vector3::new(p[0], p[1], p[2])))
+        .collect::<Vec<_>>();
+    let mean = lab.iter().fold(Vector3::zeros(), |a, b| a + b) / lab.len() as f32;
+    let sigma = (SALIENCY_BLUR_RADIUS as f32 / 2.0).max(0.5);
+    let blur = |k: usize| {
+        gaussian_blur(
+            &lab.iter().map(|c| c[k]).collect::<Vec<_>>(),
+            width,
+            height,
+            SALIENCY_BLUR_RADIUS,
+            sigma,
+        )
+    };
+    let (l, a, b) = (blur(0), blur(1), blur(2));
+    normalize_max(
+        (0..lab.len())
+            .map(|i| (Vector3::new(l[i], a[i], b[i]) - mean).norm())
+            .collect(),
+    )
+}
+
+fn edge_distance(image: &DynamicImage, threshold: f32, falloff: f32) -> Vec<f32> {
+    let (width, height) = image.dimensions();
+    let is_edge = gradient_magnitude(image)
+        .iter()
+        .map(|&m| m > threshold)
+        .collect::<Vec<_>>();
+    if !is_edge.iter().any(|&e| e) {
+        return vec![0.0; is_edge.len()];
+    }
+    euclidean_distance_transform(&is_edge, width, height)
+        .distances
+        .iter()
+        .map(|&d2| (-(d2 as f32) / (2.0 * falloff.max(f32::EPSILON).powi(2))).exp())
+        .collect()
+}
+
+// A non-positive gamma would turn zero importance into inf or NaN.
+fn check_gamma(gamma: f32) -> Result<()> {
+    if gamma.is_nan() || gamma <= 0.0 {
+        return Err(anyhow!("gamma must be positive but is {}", gamma));
+    }
+    Ok(())
+}
+
+fn importance_from_image(image: &DynamicImage, options: &ImportanceOptions) -> Result<Vec<f32>> {
+    check_gamma(options.gamma)?;
+    let total_weight: f32 = options.terms.iter().map(|t| t.weight).sum();
+    if options.terms.is_empty() || total_weight <= 0.0 {
+        return Err(anyhow!(
+            "at least one importance method needs a positive weight"
+        ));
+    }
+
+    let (width, height) = image.dimensions();
+    let mut importance = vec![0.0; (width * height) as usize];
+    for term in &options.terms {
+        if term.weight <= 0.0 {
+            continue;
+        }
+        let values = match term.method {
+            ImportanceMethod::Gradient => gradient_magnitude(image),
+            ImportanceMethod::Contrast => local_contrast(image, options.radius),
+            ImportanceMethod::Saliency => frequency_tuned_saliency(image),
+            ImportanceMethod::EdgeDistance => {
+                edge_distance(image, options.edge_threshold, options.falloff)
+            }
+        };
+        for (v, m) in importance.iter_mut().zip(values.iter()) {
+            *v += m * term.weight / total_weight;
+        }
+    }
+
+    // A flat result would be saved as an all-black map that Maps::load rejects on the next run.
+    let min = importance.iter().cloned().fold(f32::MAX, f32::min);
+    let max = importance.iter().cloned().fold(f32::MIN, f32::max);
+    if max <= min {
+        return Err(anyhow!(
+            "the importance is the same everywhere in the image, so no pixel would be preferred"
+        ));
+    }
+
+    Ok(normalize_range(importance)
+        .iter()
+        .map(|v| v.powf(options.gamma))
+        .collect())
+}
+
+// Save the importance as 8-bit grayscale like the other input maps.
+pub fn save_importance(importance: &[f32], width: u32, height: u32, output: &Path) -> Result<()> {
+    let image = GrayImage::from_fn(width, height, |x, y| {
+        let v = importance[(y * width + x) as usize];
+        Luma([(v.clamp(0.0, 1.0) * 255.0).round() as u8])
+    });
+    image.save(output)?;
+    Ok(())
+}
+
+pub fn create_importance_map(
+    input: &Path,
+    output: &Path,
+    options: &ImportanceOptions,
+) -> Result<()> {
+    let image = image::open(input)?;
+    let (width, height) = image.dimensions();
+    let importance = importance_from_image(&image, options)?;
+    save_importance(&importance, width, height, output)
+}
+
+// Measure how far a previous result is from the target colors with DE2000 and blur it into an
+// importance map, so the next run puts strokes where the colors do not match.
+pub fn create_importance_map_from_residual(
+    target: &Path,
+    result: &Path,
+    output: &Path,
+    blur_radius: u32,
+    gamma: f32,
+) -> Result<()> {
+    check_gamma(gamma)?;
+    let target = image::open(target)?;
+    let (width, height) = target.dimensions();
+    // Results are usually rendered at another size, so resample them like error_reference.
+    let result = image::open(result)?;
+    let result = if result.dimensions() == (width, height) {
+        result.to_rgb()
+    } else {
+        result
+            .resize_exact(width, height, FilterType::Triangle)
+            .to_rgb()
+    };
+
+    // Transparent parts of the color map are never painted, so they carry no error.
+    let residual = target
+        .pixels()
+        .zip(result.pixels())
+        .map(|((_, _, t), r)| {
+            if (t[3] as f32 / 255.0) < PAINTABLE_ALPHA {
+                0.0
+            } else {
+                DE2000::from_rgb(&[t[0], t[1], t[2]], &[r[0], r[1], r[2]])
+            }
+        })
+        .collect::<Vec<_>>();
+    let paintable_num = target
+        .pixels()
+        .filter(|(_, _, t)| (t[3] as f32 / 255.0) >= PAINTABLE_ALPHA)
+        .count()
+        .max(1);
+    let mean = residual.iter().sum::<f32>() / paintable_num as f32;
+    println!(">> Mean DE2000: {:.3}", mean);
+    if !residual.iter().any(|&e| e > 0.0) {
+        return Err(anyhow!(
+            "the result matches the color map everywhere, so there is no error to use as importance"
+        ));
+    }
+
+    let residual = if blur_radius > 0 {
+        let sigma = (blur_radius as f32 / 2.0).max(0.5);
+        gaussian_blur(&residual, width, height, blur_radius, sigma)
+    } else {
+        residual
+    };
+    let importance = normalize_max(residual)
+        .iter()
+        .map(|v| v.powf(gamma))
+        .collect::<Vec<_>>();
+    save_importance(&importance, width, height, output)
+}
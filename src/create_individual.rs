@@ -1,65 +1,32 @@
 use std::path::Path;
 
 use anyhow::Result;
-use image::{self, GenericImageView};
-use na::{Vector2, Vector3};
-use nalgebra as na;
 
+use crate::config::RunConfig;
 use crate::individual::Individual;
+use crate::maps::Maps;
+use crate::palette::Palette;
 use crate::renderer;
 use crate::resources::Resources;
 
-pub fn create_individual(
-    color_map: &str,
-    dir_map: &str,
-    importance_map: &str,
-    output_path: &str,
-    stroke_num: u32,
-    stroke_thickness: f32,
-) -> Result<()> {
-    let color_map = image::open(color_map).unwrap();
-    let dir_map = image::open(dir_map).unwrap();
-    let importance_map = image::open(importance_map).unwrap();
-
-    if color_map.dimensions() != dir_map.dimensions()
-        || dir_map.dimensions() != importance_map.dimensions()
-    {
-        panic!("The maps are different sizes.")
-    }
+pub fn create_individual(config: &RunConfig) -> Result<()> {
+    let output_path = config.output_path_str()?;
 
-    let (width, height) = color_map.dimensions();
-    let (width, height) = (width as i32, height as i32);
+    let maps = Maps::load(config)?;
+    let (width, height) = (maps.width, maps.height);
     let aspect = width as f64 / height as f64;
 
-    let colors = color_map
-        .pixels()
-        .map(|(_, _, p)| Vector3::new(p[0], p[1], p[2]))
-        .collect::<Vec<_>>();
-
-    let directions = dir_map
-        .pixels()
-        .map(|(_, _, p)| {
-            Vector2::new(
-                p[0] as f32 / 255.0 * 2.0 - 1.0,
-                p[1] as f32 / 255.0 * 2.0 - 1.0,
-            )
-            .normalize()
-        })
-        .collect::<Vec<_>>();
-
-    let importance = importance_map
-        .pixels()
-        .map(|(_, _, p)| p[0] as f32 / 255.0)
-        .collect::<Vec<_>>();
+    let palette = Palette::from_config(&config.palette, &maps.paintable_colors())?;
+    if let Some(palette) = &palette {
+        println!("palette: {} colors", palette.len());
+    }
 
     let individual = Individual::new(
-        &colors,
-        &directions,
-        &importance,
-        width,
-        height,
-        stroke_num,
-        stroke_thickness,
+        &maps,
+        &config.brush,
+        &config.seeding,
+        &config.regions,
+        palette.as_ref(),
     );
 
     let res = Resources::from_relative_exe_path(Path::new("assets"))?;
@@ -74,6 +41,7 @@ pub fn create_individual(
         let (major, minor) = gl_attr.context_version();
         println!("OK: init OpenGL: version={}.{}", major, minor);
         gl_attr.set_multisample_samples(4);
+        gl_attr.set_framebuffer_srgb_compatible(true);
     }
 
     let mut window = video_subsystem
@@ -87,9 +55,24 @@ pub fn create_individual(
     let _gl_context = window.gl_create_context().unwrap();
     gl::load_with(|s| video_subsystem.gl_get_proc_address(s) as *const std::os::raw::c_void);
 
-    let mut renderer = renderer::Renderer::new(width, height, width, height, &res)?;
+    let mut renderer = renderer::Renderer::new(
+        width,
+        height,
+        width,
+        height,
+        &config.render,
+        &config.brush,
+        &res,
+    )?;
+    renderer.set_alpha_mask(maps.alpha.clone());
 
     renderer.render_to_file(&individual, output_path)?;
+    if config.render.impasto.enabled {
+        renderer.render_impasto_to_file(&individual, output_path)?;
+    }
+    if let Some(palette) = &palette {
+        palette.report(&individual, output_path)?;
+    }
 
     let mut event_pump = sdl.event_pump().unwrap();
     'main: loop {
@@ -0,0 +1,312 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Luma, Rgb};
+use na::Vector2;
+use nalgebra as na;
+use thiserror::Error;
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+// Storage format of direction maps.
+// Rg8 and Rg16 store (dir + 1) / 2 in R and G. Angle8 and Angle16 store an angle in 0 to pi in
+// one channel; strokes extend both ways, so the angle can wrap at pi.
+// Npy stores a (height, width, 2) float32 array.
+#[derive(Error, Debug)]
+pub enum DirectionMapError {
+    #[error("failed to read direction map {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("failed to open direction map {path}: {source}")]
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    #[error("unsupported channel layout of direction map {path}: {layout}")]
+    UnsupportedLayout { path: PathBuf, layout: &'static str },
+    #[error("invalid npy file {path}: {reason}")]
+    InvalidNpy { path: PathBuf, reason: &'static str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirectionEncoding {
+    Rg8,
+    Rg16,
+    Angle8,
+    Angle16,
+    Npy,
+}
+
+impl FromStr for DirectionEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rg8" => Ok(DirectionEncoding::Rg8),
+            "rg16" => Ok(DirectionEncoding::Rg16),
+            "angle8" => Ok(DirectionEncoding::Angle8),
+            "angle16" => Ok(DirectionEncoding::Angle16),
+            "npy" => Ok(DirectionEncoding::Npy),
+            _ => Err(anyhow!("unknown direction encoding: {}", s)),
+        }
+    }
+}
+
+impl DirectionEncoding {
+    // Derived from the extension if no format is given.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("npy") => DirectionEncoding::Npy,
+            _ => DirectionEncoding::Rg8,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectionOutput {
+    pub path: PathBuf,
+    pub encoding: DirectionEncoding,
+}
+
+fn angle_to_direction(angle: f32) -> Vector2<f32> {
+    Vector2::new(angle.cos(), angle.sin())
+}
+
+fn direction_to_angle(d: &Vector2<f32>) -> f32 {
+    let angle = d.y.atan2(d.x);
+    if angle < 0.0 {
+        angle + std::f32::consts::PI
+    } else {
+        angle
+    }
+    .min(std::f32::consts::PI)
+}
+
+// Zero and non-finite vectors become zero, which is treated as having no direction.
+fn normalize_or_zero(v: Vector2<f32>) -> Vector2<f32> {
+    let length = v.norm();
+    if length.is_finite() && length > 0.0 {
+        v / length
+    } else {
+        Vector2::zeros()
+    }
+}
+
+fn rg16(r: u16, g: u16) -> Vector2<f32> {
+    normalize_or_zero(Vector2::new(
+        r as f32 / 65535.0 * 2.0 - 1.0,
+        g as f32 / 65535.0 * 2.0 - 1.0,
+    ))
+}
+
+// Read .npy files as npy, and images by detecting the format from channels and bit depth.
+pub fn read_directions(path: &Path) -> Result<(u32, u32, Vec<Vector2<f32>>), DirectionMapError> {
+    if path.extension().and_then(|e| e.to_str()) == Some("npy") {
+        return read_npy(path);
+    }
+
+    let image = image::open(path).map_err(|source| DirectionMapError::Image {
+        path: path.into(),
+        source,
+    })?;
+    let (width, height) = image.dimensions();
+    let directions = match &image {
+        DynamicImage::ImageLuma8(gray) => gray
+            .pixels()
+            .map(|p| angle_to_direction(p[0] as f32 / 255.0 * std::f32::consts::PI))
+            .collect(),
+        DynamicImage::ImageLuma16(gray) => gray
+            .pixels()
+            .map(|p| angle_to_direction(p[0] as f32 / 65535.0 * std::f32::consts::PI))
+            .collect(),
+        DynamicImage::ImageRgb16(rgb) => rgb.pixels().map(|p| rg16(p[0], p[1])).collect(),
+        DynamicImage::ImageRgba16(rgba) => rgba.pixels().map(|p| rg16(p[0], p[1])).collect(),
+        // The second channel could be either a direction component or alpha.
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLumaA16(_) => {
+            return Err(DirectionMapError::UnsupportedLayout {
+                path: path.into(),
+                layout: "gray with alpha",
+            });
+        }
+        _ => image
+            .pixels()
+            .map(|(_, _, p)| {
+                normalize_or_zero(Vector2::new(
+                    p[0] as f32 / 255.0 * 2.0 - 1.0,
+                    p[1] as f32 / 255.0 * 2.0 - 1.0,
+                ))
+            })
+            .collect(),
+    };
+    Ok((width, height, directions))
+}
+
+pub fn write_directions(
+    output: &DirectionOutput,
+    width: u32,
+    height: u32,
+    directions: &[Vector2<f32>],
+) -> Result<()> {
+    let path = &output.path;
+    let unit = |d: &Vector2<f32>| (d + Vector2::new(1.0, 1.0)) * 0.5;
+    match output.encoding {
+        DirectionEncoding::Rg8 => {
+            ImageBuffer::from_fn(width, height, |x, y| {
+                let d = unit(&directions[(y * width + x) as usize]);
+                Rgb([(255.0 * d.x) as u8, (255.0 * d.y) as u8, 0])
+            })
+            .save(path)?;
+        }
+        DirectionEncoding::Rg16 => {
+            let image: ImageBuffer<Rgb<u16>, Vec<u16>> =
+                ImageBuffer::from_fn(width, height, |x, y| {
+                    let d = unit(&directions[(y * width + x) as usize]);
+                    Rgb([
+                        (65535.0 * d.x).round() as u16,
+                        (65535.0 * d.y).round() as u16,
+                        0,
+                    ])
+                });
+            image.save(path)?;
+        }
+        DirectionEncoding::Angle8 => {
+            ImageBuffer::from_fn(width, height, |x, y| {
+                let angle = direction_to_angle(&directions[(y * width + x) as usize]);
+                Luma([(angle / std::f32::consts::PI * 255.0).round() as u8])
+            })
+            .save(path)?;
+        }
+        DirectionEncoding::Angle16 => {
+            let image: ImageBuffer<Luma<u16>, Vec<u16>> =
+                ImageBuffer::from_fn(width, height, |x, y| {
+                    let angle = direction_to_angle(&directions[(y * width + x) as usize]);
+                    Luma([(angle / std::f32::consts::PI * 65535.0).round() as u16])
+                });
+            image.save(path)?;
+        }
+        DirectionEncoding::Npy => write_npy(path, width, height, directions)?,
+    }
+    Ok(())
+}
+
+// Extract the value of `key` from the header dictionary as a string.
+fn npy_header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find(',').unwrap_or(rest.len())
+    };
+    Some(rest[..end].trim())
+}
+
+// Read (height, width, 2) directions or (height, width) angles in radians.
+// C-ordered float32 and float64 are supported.
+fn read_npy(path: &Path) -> Result<(u32, u32, Vec<Vector2<f32>>), DirectionMapError> {
+    let bytes = fs::read(path).map_err(|source| DirectionMapError::Io {
+        path: path.into(),
+        source,
+    })?;
+    let invalid = |reason| DirectionMapError::InvalidNpy {
+        path: path.into(),
+        reason,
+    };
+    if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        _ => return Err(invalid("unsupported version")),
+    };
+    let header = bytes
+        .get(header_start..header_start + header_len)
+        .and_then(|h| std::str::from_utf8(h).ok())
+        .ok_or_else(|| invalid("bad header"))?;
+    let data = &bytes[header_start + header_len..];
+
+    match npy_header_value(header, "fortran_order") {
+        Some("False") => {}
+        Some("True") => return Err(invalid("fortran order is not supported")),
+        _ => return Err(invalid("missing fortran_order")),
+    }
+    let values: Vec<f32> = match npy_header_value(header, "descr")
+        .map(|d| d.trim_matches(|c| c == '\'' || c == '"'))
+    {
+        Some("<f4") => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        Some("<f8") => data
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32)
+            .collect(),
+        _ => {
+            return Err(invalid(
+                "only little-endian float32 and float64 are supported",
+            ))
+        }
+    };
+    let shape = npy_header_value(header, "shape")
+        .ok_or_else(|| invalid("missing shape"))?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid("bad shape"))?;
+
+    let (height, width, directions) = match shape[..] {
+        [height, width, 2] => (
+            height,
+            width,
+            values
+                .chunks_exact(2)
+                .map(|v| normalize_or_zero(Vector2::new(v[0], v[1])))
+                .collect::<Vec<_>>(),
+        ),
+        [height, width] => (
+            height,
+            width,
+            values.iter().map(|&a| angle_to_direction(a)).collect(),
+        ),
+        _ => {
+            return Err(invalid(
+                "shape must be (height, width, 2) or (height, width)",
+            ))
+        }
+    };
+    if directions.len() != (width * height) as usize {
+        return Err(invalid("data is shorter than the shape"));
+    }
+    Ok((width, height, directions))
+}
+
+fn write_npy(path: &Path, width: u32, height: u32, directions: &[Vector2<f32>]) -> Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}, 2), }}",
+        height, width
+    );
+    // Pad with spaces so that the magic through the header newline is a multiple of 64 bytes.
+    let unpadded = NPY_MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut bytes = NPY_MAGIC.to_vec();
+    bytes.extend_from_slice(&[1, 0]);
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for d in directions {
+        bytes.extend_from_slice(&d.x.to_le_bytes());
+        bytes.extend_from_slice(&d.y.to_le_bytes());
+    }
+    fs::write(path, bytes)?;
+    Ok(())
+}
@@ -0,0 +1,553 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GenericImageView, GrayImage, RgbImage};
+use na::Vector2;
+use nalgebra as na;
+use rayon::prelude::*;
+
+use crate::direction_encoding::{read_directions, write_directions, DirectionOutput};
+use crate::distance_transform::euclidean_distance_transform;
+
+// How much ETF weighs the gradient magnitude difference to the neighbor
+const ETF_MAGNITUDE_SHARPNESS: f32 = 1.0;
+
+// Ratio of the kernel spread along the direction to across it for anisotropic
+const ANISOTROPY: f32 = 4.0;
+
+// Directions shorter than this are treated as undefined.
+const DEGENERATE_LENGTH: f32 = 1e-6;
+
+// Stroke direction per pixel, in the same order as the image rows. y points down.
+pub struct DirectionField {
+    pub width: u32,
+    pub height: u32,
+    pub directions: Vec<Vector2<f32>>,
+    // How reliable each direction is, 0.0 to 1.0. Filled-in pixels get 0.0.
+    pub confidence: Vec<f32>,
+}
+
+// How to fill pixels without a direction. Propagate uses the nearest valid pixel, Angle the
+// default angle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DegenerateFill {
+    Propagate,
+    Angle,
+}
+
+impl FromStr for DegenerateFill {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "propagate" => Ok(DegenerateFill::Propagate),
+            "angle" => Ok(DegenerateFill::Angle),
+            _ => Err(anyhow!("unknown degenerate fill: {}", s)),
+        }
+    }
+}
+
+fn is_degenerate(d: &Vector2<f32>) -> bool {
+    let length = d.norm();
+    length.is_nan() || length <= DEGENERATE_LENGTH
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirectionMethod {
+    StructureTensor,
+    Etf,
+}
+
+impl FromStr for DirectionMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "structure-tensor" => Ok(DirectionMethod::StructureTensor),
+            "etf" => Ok(DirectionMethod::Etf),
+            _ => Err(anyhow!("unknown direction method: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmoothingMethod {
+    Gaussian,
+    Bilateral,
+    Anisotropic,
+}
+
+impl FromStr for SmoothingMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gaussian" => Ok(SmoothingMethod::Gaussian),
+            "bilateral" => Ok(SmoothingMethod::Bilateral),
+            "anisotropic" => Ok(SmoothingMethod::Anisotropic),
+            _ => Err(anyhow!("unknown smoothing method: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Smoothing {
+    pub method: SmoothingMethod,
+    pub radius: u32,
+    pub iterations: u32,
+    // Color difference tolerated by bilateral, on a 0 to 255 scale
+    pub color_sigma: f32,
+    // Image whose colors bilateral compares
+    pub guide: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Degenerate {
+    pub fill: DegenerateFill,
+    // Angle in degrees used by DegenerateFill::Angle, or when no pixel is valid
+    pub default_angle: f32,
+    // If given, the confidence is written to this path.
+    pub confidence_output: Option<PathBuf>,
+}
+
+// Double the angle so that d and -d average as the same orientation.
+pub fn double_angle(d: &Vector2<f32>) -> Vector2<f32> {
+    Vector2::new(d.x * d.x - d.y * d.y, 2.0 * d.x * d.y)
+}
+
+pub fn half_angle(v: &Vector2<f32>) -> Vector2<f32> {
+    let theta = 0.5 * v.y.atan2(v.x);
+    Vector2::new(theta.cos(), theta.sin())
+}
+
+impl DirectionField {
+    pub fn new(width: u32, height: u32, directions: Vec<Vector2<f32>>) -> Self {
+        let confidence = directions
+            .iter()
+            .map(|d| if is_degenerate(d) { 0.0 } else { 1.0 })
+            .collect();
+        Self {
+            width,
+            height,
+            directions,
+            confidence,
+        }
+    }
+
+    // read_directions detects the format.
+    pub fn load(path: &Path) -> Result<Self> {
+        let (width, height, directions) = read_directions(path)?;
+        Ok(Self::new(width, height, directions))
+    }
+
+    pub fn save(&self, output: &DirectionOutput) -> Result<()> {
+        write_directions(output, self.width, self.height, &self.directions)
+    }
+
+    pub fn from_image(
+        image: &DynamicImage,
+        method: DirectionMethod,
+        radius: u32,
+        iterations: u32,
+    ) -> Self {
+        match method {
+            DirectionMethod::StructureTensor => Self::from_structure_tensor(image, radius),
+            DirectionMethod::Etf => Self::from_edge_tangent_flow(image, radius, iterations),
+        }
+    }
+
+    // The direction is the eigenvector of the smaller eigenvalue of the structure tensor, the
+    // smoothed outer product of the gradients.
+    pub fn from_structure_tensor(image: &DynamicImage, radius: u32) -> Self {
+        let (width, height) = image.dimensions();
+        let gradients = sobel(image);
+        let sigma = (radius as f32 / 2.0).max(0.5);
+        let blur = |f: &dyn Fn(&Vector2<f32>) -> f32| {
+            gaussian_blur(
+                &gradients.iter().map(f).collect::<Vec<_>>(),
+                width,
+                height,
+                radius,
+                sigma,
+            )
+        };
+        let e = blur(&|g| g.x * g.x);
+        let f = blur(&|g| g.x * g.y);
+        let g = blur(&|g| g.y * g.y);
+
+        let directions = (0..gradients.len())
+            .map(|i| {
+                if e[i] + g[i] <= 0.0 {
+                    return Vector2::zeros();
+                }
+                // The larger eigenvalue's eigenvector is the gradient, so take the orthogonal one.
+                let theta = 0.5 * (2.0 * f[i]).atan2(e[i] - g[i]);
+                Vector2::new(-theta.sin(), theta.cos())
+            })
+            .collect();
+
+        Self::new(width, height, directions)
+    }
+
+    // Edge Tangent Flow by Kang et al. Iteratively smooths the tangents orthogonal to the
+    // gradient, favoring pixels with a large gradient.
+    pub fn from_edge_tangent_flow(image: &DynamicImage, radius: u32, iterations: u32) -> Self {
+        let (width, height) = image.dimensions();
+        let gradients = sobel(image);
+        let magnitudes = gradients.iter().map(|g| g.norm()).collect::<Vec<_>>();
+        let max_magnitude = magnitudes.iter().cloned().fold(0.0, f32::max);
+        let magnitudes = magnitudes
+            .iter()
+            .map(|m| m / max_magnitude.max(f32::EPSILON))
+            .collect::<Vec<_>>();
+
+        let mut tangents = gradients
+            .iter()
+            .map(|g| {
+                if g.norm() > 0.0 {
+                    Vector2::new(-g.y, g.x).normalize()
+                } else {
+                    Vector2::zeros()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..iterations {
+            tangents = (0..(width * height) as usize)
+                .into_par_iter()
+                .map(|i| etf_step(&tangents, &magnitudes, width, height, radius, i))
+                .collect();
+        }
+
+        Self::new(width, height, tangents)
+    }
+
+    // Fill pixels without a direction. default_angle is in degrees, 0 right and 90 down.
+    pub fn fill_degenerate(&mut self, fill: DegenerateFill, default_angle: f32) {
+        let default_direction = {
+            let angle = default_angle.to_radians();
+            Vector2::new(angle.cos(), angle.sin())
+        };
+        let valid = self
+            .directions
+            .iter()
+            .map(|d| !is_degenerate(d))
+            .collect::<Vec<_>>();
+        for (c, &v) in self.confidence.iter_mut().zip(valid.iter()) {
+            if !v {
+                *c = 0.0;
+            }
+        }
+        if valid.iter().all(|&v| v) {
+            return;
+        }
+
+        let nearest = if fill == DegenerateFill::Propagate && valid.iter().any(|&v| v) {
+            Some(euclidean_distance_transform(&valid, self.width, self.height).features)
+        } else {
+            None
+        };
+        for i in 0..self.directions.len() {
+            if valid[i] {
+                continue;
+            }
+            self.directions[i] = match nearest.as_ref().and_then(|n| n[i]) {
+                Some((x, y)) => self.directions[(y * self.width + x) as usize],
+                None => default_direction,
+            };
+        }
+    }
+
+    // Blend self by `weights` and other by the rest. The angles are doubled so that d and -d
+    // are not told apart.
+    pub fn blend(&self, other: &DirectionField, weights: &[f32]) -> Result<Self> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return Err(anyhow!(
+                "cannot blend a {}x{} direction map with a {}x{} one",
+                self.width,
+                self.height,
+                other.width,
+                other.height
+            ));
+        }
+        let directions = (0..self.directions.len())
+            .map(|i| {
+                let w = weights[i].clamp(0.0, 1.0);
+                let v = double_angle(&self.directions[i]) * w
+                    + double_angle(&other.directions[i]) * (1.0 - w);
+                if v.norm() > 0.0 {
+                    half_angle(&v)
+                } else {
+                    self.directions[i]
+                }
+            })
+            .collect();
+        let confidence = (0..self.confidence.len())
+            .map(|i| {
+                let w = weights[i].clamp(0.0, 1.0);
+                self.confidence[i] * w + other.confidence[i] * (1.0 - w)
+            })
+            .collect();
+        Ok(Self {
+            width: self.width,
+            height: self.height,
+            directions,
+            confidence,
+        })
+    }
+
+    // Save the confidence as a grayscale image.
+    pub fn save_confidence(&self, path: &Path) -> Result<()> {
+        let mut image = GrayImage::new(self.width, self.height);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let c = self.confidence[(y * self.width + x) as usize];
+            pixel[0] = (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+        image.save(path)?;
+        Ok(())
+    }
+
+    pub fn smooth(&self, smoothing: &Smoothing, guide: Option<&RgbImage>) -> Result<Self> {
+        if let Some(guide) = guide {
+            if guide.dimensions() != (self.width, self.height) {
+                return Err(anyhow!(
+                    "the guide image is {}x{} but the direction map is {}x{}",
+                    guide.width(),
+                    guide.height(),
+                    self.width,
+                    self.height
+                ));
+            }
+        }
+        let guide = match smoothing.method {
+            SmoothingMethod::Bilateral => {
+                Some(guide.ok_or_else(|| anyhow!("bilateral smoothing needs a guide image"))?)
+            }
+            _ => None,
+        };
+
+        let mut directions = self.directions.clone();
+        for _ in 0..smoothing.iterations {
+            directions = match smoothing.method {
+                SmoothingMethod::Gaussian => self.gaussian_step(&directions, smoothing),
+                _ => (0..directions.len())
+                    .into_par_iter()
+                    .map(|i| self.local_step(&directions, smoothing, guide, i))
+                    .collect(),
+            };
+        }
+
+        Ok(Self {
+            width: self.width,
+            height: self.height,
+            directions,
+            confidence: self.confidence.clone(),
+        })
+    }
+
+    // Apply a Gaussian to each component of the doubled-angle vectors.
+    fn gaussian_step(
+        &self,
+        directions: &[Vector2<f32>],
+        smoothing: &Smoothing,
+    ) -> Vec<Vector2<f32>> {
+        let sigma = (smoothing.radius as f32 / 2.0).max(0.5);
+        let doubled = directions
+            .iter()
+            .zip(self.confidence.iter())
+            .map(|(d, c)| double_angle(d) * *c)
+            .collect::<Vec<_>>();
+        let blur = |f: &dyn Fn(&Vector2<f32>) -> f32| {
+            gaussian_blur(
+                &doubled.iter().map(f).collect::<Vec<_>>(),
+                self.width,
+                self.height,
+                smoothing.radius,
+                sigma,
+            )
+        };
+        let xs = blur(&|v| v.x);
+        let ys = blur(&|v| v.y);
+        xs.iter()
+            .zip(ys.iter())
+            .zip(directions.iter())
+            .map(|((&x, &y), d)| {
+                let v = Vector2::new(x, y);
+                if v.norm() > 0.0 {
+                    half_angle(&v)
+                } else {
+                    *d
+                }
+            })
+            .collect()
+    }
+
+    // bilateral weighs pixels of similar color, anisotropic weighs aligned pixels lying along
+    // the direction.
+    fn local_step(
+        &self,
+        directions: &[Vector2<f32>],
+        smoothing: &Smoothing,
+        guide: Option<&RgbImage>,
+        i: usize,
+    ) -> Vector2<f32> {
+        let (w, h, r) = (
+            self.width as i32,
+            self.height as i32,
+            smoothing.radius as i32,
+        );
+        let (x, y) = (i as i32 % w, i as i32 / w);
+        let sigma = (smoothing.radius as f32 / 2.0).max(0.5);
+        let d = directions[i];
+        let n = Vector2::new(-d.y, d.x);
+        let color = |x: i32, y: i32| {
+            guide.map(|g| {
+                let p = g.get_pixel(x as u32, y as u32);
+                na::Vector3::new(p[0] as f32, p[1] as f32, p[2] as f32)
+            })
+        };
+        let c = color(x, y);
+
+        let mut sum = Vector2::zeros();
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || nx >= w || ny < 0 || ny >= h || dx * dx + dy * dy > r * r {
+                    continue;
+                }
+                let dj = directions[(ny * w + nx) as usize];
+                let offset = Vector2::new(dx as f32, dy as f32);
+                let weight = match smoothing.method {
+                    SmoothingMethod::Anisotropic => {
+                        let along = offset.dot(&d) / sigma;
+                        let across = offset.dot(&n) / (sigma / ANISOTROPY);
+                        (-(along * along + across * across) / 2.0).exp() * d.dot(&dj).abs()
+                    }
+                    _ => {
+                        let spatial = (-offset.norm_squared() / (2.0 * sigma * sigma)).exp();
+                        let range = match (c, color(nx, ny)) {
+                            (Some(c0), Some(c1)) => (-(c0 - c1).norm_squared()
+                                / (2.0 * smoothing.color_sigma * smoothing.color_sigma))
+                                .exp(),
+                            _ => 1.0,
+                        };
+                        spatial * range
+                    }
+                };
+                sum += double_angle(&dj) * (weight * self.confidence[(ny * w + nx) as usize]);
+            }
+        }
+        if sum.norm() > 0.0 {
+            half_angle(&sum)
+        } else {
+            d
+        }
+    }
+}
+
+// Average the tangents within `radius`, weighing pixels with a large gradient and a similar
+// orientation more.
+fn etf_step(
+    tangents: &[Vector2<f32>],
+    magnitudes: &[f32],
+    width: u32,
+    height: u32,
+    radius: u32,
+    i: usize,
+) -> Vector2<f32> {
+    let (w, h, r) = (width as i32, height as i32, radius as i32);
+    let (x, y) = (i as i32 % w, i as i32 / w);
+    let t = tangents[i];
+    let m = magnitudes[i];
+    let mut sum = Vector2::zeros();
+    for dy in -r..=r {
+        for dx in -r..=r {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || nx >= w || ny < 0 || ny >= h || dx * dx + dy * dy > r * r {
+                continue;
+            }
+            let j = (ny * w + nx) as usize;
+            let tj = tangents[j];
+            let dot = t.dot(&tj);
+            let wm = (1.0 + (ETF_MAGNITUDE_SHARPNESS * (magnitudes[j] - m)).tanh()) / 2.0;
+            let wd = if t.norm() > 0.0 { dot.abs() } else { 1.0 };
+            // Flip tangents pointing the opposite way before adding them.
+            let sign = if dot < 0.0 { -1.0 } else { 1.0 };
+            sum += tj * (sign * wm * wd);
+        }
+    }
+    if sum.norm() > 0.0 {
+        sum.normalize()
+    } else {
+        t
+    }
+}
+
+// Sobel gradient of the luminance. y points down.
+pub fn sobel(image: &DynamicImage) -> Vec<Vector2<f32>> {
+    let luma = image.to_luma();
+    let (width, height) = (luma.width() as i32, luma.height() as i32);
+    let at = |x: i32, y: i32| {
+        let x = x.max(0).min(width - 1);
+        let y = y.max(0).min(height - 1);
+        luma.get_pixel(x as u32, y as u32)[0] as f32 / 255.0
+    };
+    (0..(width * height))
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x - 1, y)
+                - at(x - 1, y + 1);
+            let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x, y - 1)
+                - at(x + 1, y - 1);
+            Vector2::new(gx, gy)
+        })
+        .collect()
+}
+
+// Sobel gradient magnitude of the luminance, normalized to a maximum of 1.0.
+pub fn gradient_magnitude(image: &DynamicImage) -> Vec<f32> {
+    let magnitude = sobel(image).iter().map(|g| g.norm()).collect::<Vec<_>>();
+    let max = magnitude.iter().cloned().fold(0.0, f32::max);
+    if max > 0.0 {
+        magnitude.iter().map(|m| m / max).collect()
+    } else {
+        magnitude
+    }
+}
+
+// Separable Gaussian filter. Edge pixels are extended.
+pub fn gaussian_blur(data: &[f32], width: u32, height: u32, radius: u32, sigma: f32) -> Vec<f32> {
+    let r = radius as i32;
+    let kernel = (-r..=r)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let total: f32 = kernel.iter().sum();
+    let kernel = kernel.iter().map(|k| k / total).collect::<Vec<_>>();
+
+    let (w, h) = (width as i32, height as i32);
+    let pass = |data: &[f32], horizontal: bool| {
+        (0..(w * h))
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = (i % w, i / w);
+                (-r..=r)
+                    .map(|k| {
+                        let (sx, sy) = if horizontal {
+                            ((x + k).max(0).min(w - 1), y)
+                        } else {
+                            (x, (y + k).max(0).min(h - 1))
+                        };
+                        data[(sy * w + sx) as usize] * kernel[(k + r) as usize]
+                    })
+                    .sum::<f32>()
+            })
+            .collect::<Vec<_>>()
+    };
+    pass(&pass(data, true), false)
+}
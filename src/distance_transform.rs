@@ -0,0 +1,101 @@
+use rayon::prelude::*;
+
+// Exact Euclidean distance transform by Felzenszwalb and Huttenlocher.
+// `distances` is the squared distance to the nearest feature, `features` its coordinates.
+pub struct DistanceTransform {
+    pub distances: Vec<f64>,
+    pub features: Vec<Option<(u32, u32)>>,
+}
+
+// Lower envelope of the parabolas rooted at each point of f. Returns the minimum at each point
+// and the parabola it comes from. Points where f is infinite have no parabola.
+fn lower_envelope(f: &[f64]) -> Vec<(f64, Option<usize>)> {
+    let n = f.len();
+    let intersection = |p: usize, q: usize| {
+        ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2.0 * q as f64 - 2.0 * p as f64)
+    };
+
+    // v holds the parabola vertices of the envelope, z[k] the left end of the range where v[k] wins
+    let mut v: Vec<usize> = Vec::with_capacity(n);
+    let mut z: Vec<f64> = Vec::with_capacity(n);
+    for (q, fq) in f.iter().enumerate() {
+        if !fq.is_finite() {
+            continue;
+        }
+        let mut s = f64::NEG_INFINITY;
+        while let Some(&p) = v.last() {
+            s = intersection(p, q);
+            if s <= z[z.len() - 1] {
+                v.pop();
+                z.pop();
+            } else {
+                break;
+            }
+        }
+        v.push(q);
+        z.push(s);
+    }
+
+    if v.is_empty() {
+        return vec![(f64::INFINITY, None); n];
+    }
+
+    let mut k = 0;
+    (0..n)
+        .map(|x| {
+            while k + 1 < v.len() && z[k + 1] < x as f64 {
+                k += 1;
+            }
+            let p = v[k];
+            let d = x as f64 - p as f64;
+            (d * d + f[p], Some(p))
+        })
+        .collect()
+}
+
+pub fn euclidean_distance_transform(
+    is_feature: &[bool],
+    width: u32,
+    height: u32,
+) -> DistanceTransform {
+    let (width, height) = (width as usize, height as usize);
+
+    // Vertical distances per column
+    let columns = (0..width)
+        .into_par_iter()
+        .map(|x| {
+            let f = (0..height)
+                .map(|y| {
+                    if is_feature[y * width + x] {
+                        0.0
+                    } else {
+                        f64::INFINITY
+                    }
+                })
+                .collect::<Vec<_>>();
+            lower_envelope(&f)
+        })
+        .collect::<Vec<_>>();
+
+    // Per row, take the horizontal envelope with the vertical distances as heights.
+    let rows = (0..height)
+        .into_par_iter()
+        .map(|y| {
+            let f = (0..width).map(|x| columns[x][y].0).collect::<Vec<_>>();
+            lower_envelope(&f)
+                .into_iter()
+                .map(|(d, fx)| {
+                    let feature =
+                        fx.and_then(|fx| columns[fx][y].1.map(|fy| (fx as u32, fy as u32)));
+                    (d, feature)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let (distances, features) = rows.into_iter().flatten().unzip();
+    DistanceTransform {
+        distances,
+        features,
+    }
+}
@@ -5,84 +5,51 @@ use std::rc::Rc;
 
 use anyhow::Result;
 use chrono::Local;
-use image::{self, GenericImageView};
-use na::{Vector2, Vector3};
-use nalgebra as na;
 use rand::distributions::WeightedIndex;
 use rand::prelude::*;
 
+use crate::config::{RunConfig, SeedingStrategy};
 use crate::individual::Individual;
+use crate::maps::Maps;
+use crate::palette::Palette;
 use crate::renderer;
 use crate::resources::Resources;
 
-pub fn genetic_algorithm(
-    color_map: &str,
-    dir_map: &str,
-    importance_map: &str,
-    output_path: &str,
-    stroke_num: u32,
-    stroke_thickness: f32,
-    population_size: u32,
-    generation: usize,
-    save_generation: Vec<usize>,
-    save_generation_step: usize,
-    save_width: i32,
-    save_height: i32,
-    d_value: i32,
-    save_sequence: Option<usize>,
-) -> Result<()> {
-    const PROBABILITY_OF_MUTATION: f64 = 0.35;
+pub fn genetic_algorithm(config: &RunConfig) -> Result<()> {
     // const PROBABILITY_CROSSOVER_BIAS: f64 = 50.0;
     // const DISTANCE_RATE: f64 = 0.5;
 
-    println!("[{}] Start GA...", Local::now());
-
-    let color_map = image::open(color_map).unwrap();
-    let dir_map = image::open(dir_map).unwrap();
-    let importance_map = image::open(importance_map).unwrap();
+    let population_size = config.ga.population_size;
+    let generation = config.ga.generation;
+    let d_value = config.ga.d_value;
+    let save_sequence = config.output.save_sequence;
+    let output_path = config.output_path_str()?;
 
-    if color_map.dimensions() != dir_map.dimensions()
-        || dir_map.dimensions() != importance_map.dimensions()
-    {
-        panic!("The maps are different sizes.")
-    }
+    println!("[{}] Start GA...", Local::now());
 
-    let (width, height) = color_map.dimensions();
-    let (width, height) = (width as i32, height as i32);
+    let mut maps = Maps::load(config)?;
+    let (width, height) = (maps.width, maps.height);
     let aspect = width as f64 / height as f64;
 
-    let window_height = 1080_u32;
-    let window_width = (window_height as f64 * aspect) as u32;
+    let save_width = config.output.width.unwrap_or(width);
+    let save_height = config.output.height.unwrap_or(height);
 
-    let colors = color_map
-        .pixels()
-        .map(|(_, _, p)| Vector3::new(p[0], p[1], p[2]))
-        .collect::<Vec<_>>();
+    let window_height = config.output.window_height;
+    let window_width = (window_height as f64 * aspect) as u32;
 
-    let directions = dir_map
-        .pixels()
-        .map(|(_, _, p)| {
-            Vector2::new(
-                p[0] as f32 / 255.0 * 2.0 - 1.0,
-                p[1] as f32 / 255.0 * 2.0 - 1.0,
-            )
-            .normalize()
-        })
-        .collect::<Vec<_>>();
+    let palette = Palette::from_config(&config.palette, &maps.paintable_colors())?;
+    if let Some(palette) = &palette {
+        println!("[{}] palette: {} colors", Local::now(), palette.len());
+    }
 
-    let importance = importance_map
-        .pixels()
-        .map(|(_, _, p)| p[0] as f32 / 255.0)
-        .collect::<Vec<_>>();
+    let mut save_generation = config.output.save_generation.clone();
+    if let Some(step) = config.output.save_generation_step.filter(|&step| step > 0) {
+        save_generation.extend((0..).map(|i| i * step).take_while(|&x| x <= generation));
+    }
 
-    let save_generation = save_generation
-        .into_iter()
-        .chain(
-            (0..)
-                .map(|i| i * save_generation_step as usize)
-                .take_while(|&x| x <= generation as usize),
-        )
-        .collect::<Vec<_>>();
+    let config_path = config.resolved_config_path()?;
+    config.save(&config_path)?;
+    println!("[{}] save config: {}", Local::now(), config_path.display());
 
     let res = Resources::from_relative_exe_path(Path::new("assets"))?;
 
@@ -96,6 +63,7 @@ pub fn genetic_algorithm(
         let (major, minor) = gl_attr.context_version();
         println!("OK: init OpenGL: version={}.{}", major, minor);
         gl_attr.set_multisample_samples(4);
+        gl_attr.set_framebuffer_srgb_compatible(true);
     }
 
     let mut window = video_subsystem
@@ -111,21 +79,35 @@ pub fn genetic_algorithm(
 
     let mut event_pump = sdl.event_pump().unwrap();
 
-    let mut renderer = renderer::Renderer::new(width, height, save_width, save_height, &res)?;
+    let mut renderer = renderer::Renderer::new(
+        width,
+        height,
+        save_width,
+        save_height,
+        &config.render,
+        &config.brush,
+        &res,
+    )?;
+    renderer.set_alpha_mask(maps.alpha.clone());
     renderer.update_viewport_size(window_width as i32, window_height as i32);
 
     println!("[{}] Generate initial population...", Local::now());
 
+    // Mutations use the brush of the region a stroke was seeded in.
+    let region_brushes = config
+        .regions
+        .iter()
+        .map(|r| r.brush(&config.brush))
+        .collect::<Vec<_>>();
+
     let mut population = (0..population_size)
         .map(|_| {
             Rc::new(RefCell::new(Individual::new(
-                &colors,
-                &directions,
-                &importance,
-                width,
-                height,
-                stroke_num,
-                stroke_thickness,
+                &maps,
+                &config.brush,
+                &config.seeding,
+                &config.regions,
+                palette.as_ref(),
             )))
         })
         .collect::<Vec<_>>();
@@ -134,7 +116,12 @@ pub fn genetic_algorithm(
     let mut population_scores = population
         .iter()
         .cloned()
-        .map(|i| (i.clone(), renderer.score(&i.borrow(), &colors, &importance)))
+        .map(|i| {
+            (
+                i.clone(),
+                renderer.score(&i.borrow(), &maps.colors, &maps.importance),
+            )
+        })
         .collect::<Vec<_>>();
     population_scores
         .sort_unstable_by(|(_, score_a), (_, score_b)| score_a.partial_cmp(score_b).unwrap());
@@ -168,8 +155,26 @@ pub fn genetic_algorithm(
     // .unwrap();
 
     // let dist05 = WeightedIndex::new(vec![1.0, 1.0]).unwrap();
-    let dist_mutation =
-        WeightedIndex::new(vec![1.0 - PROBABILITY_OF_MUTATION, PROBABILITY_OF_MUTATION]).unwrap();
+    let dist_mutation = WeightedIndex::new(vec![
+        1.0 - config.ga.mutation_probability,
+        config.ga.mutation_probability,
+    ])
+    .unwrap();
+    let dist_profile_mutation = WeightedIndex::new(vec![
+        1.0 - config.ga.profile_mutation_probability,
+        config.ga.profile_mutation_probability,
+    ])
+    .unwrap();
+    let dist_opacity_mutation = WeightedIndex::new(vec![
+        1.0 - config.ga.opacity_mutation_probability,
+        config.ga.opacity_mutation_probability,
+    ])
+    .unwrap();
+    let dist_palette_mutation = WeightedIndex::new(vec![
+        1.0 - config.ga.palette_mutation_probability,
+        config.ga.palette_mutation_probability,
+    ])
+    .unwrap();
 
     // let mut d = (top_individual.borrow().strokes.len() / 4) as i32;
     let mut d = d_value;
@@ -218,7 +223,12 @@ pub fn genetic_algorithm(
         let mut new_population_scores = new_population
             .iter()
             .cloned()
-            .map(|i| (i.clone(), renderer.score(&i.borrow(), &colors, &importance)))
+            .map(|i| {
+                (
+                    i.clone(),
+                    renderer.score(&i.borrow(), &maps.colors, &maps.importance),
+                )
+            })
             .collect::<Vec<_>>();
 
         // println!("[{}] append generation and new generation", Local::now());
@@ -279,27 +289,46 @@ pub fn genetic_algorithm(
 
             if d < 0 {
                 println!("[{}] mutation...", Local::now());
+                // Place new strokes where the current best individual misses the colors.
+                if config.seeding.strategy == SeedingStrategy::ErrorDriven {
+                    maps.residual = Some(renderer.residual(&top_individual.borrow(), &maps.colors));
+                }
                 let _ = population_scores.split_off(1);
                 let _ = population.split_off(1);
                 while population_scores.len() < population_size as usize {
                     let i_other = Individual::new(
-                        &colors,
-                        &directions,
-                        &importance,
-                        width,
-                        height,
-                        stroke_num,
-                        stroke_thickness,
+                        &maps,
+                        &config.brush,
+                        &config.seeding,
+                        &config.regions,
+                        palette.as_ref(),
                     );
                     let i = Rc::new(RefCell::new(top_individual.borrow().clone()));
                     let stroke_len = i.borrow().strokes.len();
                     for index in 0..stroke_len {
                         if dist_mutation.sample(&mut rng) == 1 {
                             i.borrow_mut().strokes[index] = i_other.strokes[index].clone();
+                            continue;
+                        }
+                        let brush = i.borrow().strokes[index]
+                            .region
+                            .map_or(&config.brush, |r| &region_brushes[r]);
+                        if dist_profile_mutation.sample(&mut rng) == 1 {
+                            i.borrow_mut().strokes[index].profile.mutate(brush);
+                        }
+                        if dist_opacity_mutation.sample(&mut rng) == 1 {
+                            i.borrow_mut().strokes[index].mutate_opacity(brush);
+                        }
+                        if let Some(palette) = &palette {
+                            if dist_palette_mutation.sample(&mut rng) == 1 {
+                                i.borrow_mut().strokes[index].mutate_palette_index(palette);
+                            }
                         }
                     }
-                    population_scores
-                        .push((i.clone(), renderer.score(&i.borrow(), &colors, &importance)));
+                    population_scores.push((
+                        i.clone(),
+                        renderer.score(&i.borrow(), &maps.colors, &maps.importance),
+                    ));
                     population.push(i.clone());
                 }
 
@@ -314,7 +343,12 @@ pub fn genetic_algorithm(
     println!("[{}] final generation", Local::now());
     let mut population_scores = population
         .iter()
-        .map(|i| (i, renderer.score(&i.borrow(), &colors, &importance)))
+        .map(|i| {
+            (
+                i,
+                renderer.score(&i.borrow(), &maps.colors, &maps.importance),
+            )
+        })
         .collect::<Vec<_>>();
     population_scores
         .sort_unstable_by(|(_, score_a), (_, score_b)| score_a.partial_cmp(score_b).unwrap());
@@ -323,6 +357,14 @@ pub fn genetic_algorithm(
     println!("[{}] final score: {}", Local::now(), top_score);
 
     renderer.render_to_file(&top_individual.borrow(), output_path)?;
+    if config.render.impasto.enabled {
+        println!("[{}] save impasto maps", Local::now());
+        renderer.render_impasto_to_file(&top_individual.borrow(), output_path)?;
+    }
+    if let Some(palette) = &palette {
+        println!("[{}] strokes per palette color", Local::now());
+        palette.report(&top_individual.borrow(), output_path)?;
+    }
 
     'main: loop {
         for event in event_pump.poll_iter() {
@@ -0,0 +1,432 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use na::{Point2, Vector2};
+use nalgebra as na;
+use rayon::prelude::*;
+use thiserror::Error;
+
+use crate::direction_field::{double_angle, half_angle, DirectionField};
+use crate::distance_transform::euclidean_distance_transform;
+
+// Spacing when placing curves on pixels
+const RASTER_STEP: f32 = 0.5;
+
+// Number of segments per Bezier curve when flattening
+const BEZIER_SEGMENTS: usize = 16;
+
+// The diffusion is solved on an image downscaled to at most this many pixels, then refined
+// while scaling back up.
+const COARSEST_PIXELS: usize = 32 * 32;
+const COARSEST_ITERATIONS: usize = 500;
+const REFINE_ITERATIONS: usize = 20;
+
+#[derive(Error, Debug)]
+pub enum GuideCurveError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    ParseJson(#[from] serde_json::Error),
+    #[error("unsupported guide curve format: {path} (expected .svg or .json)")]
+    UnsupportedFormat { path: PathBuf },
+    #[error("unsupported SVG path command '{command}' in {path}")]
+    UnsupportedPathCommand { path: PathBuf, command: char },
+    #[error("no guide curves inside the image: {path}")]
+    Empty { path: PathBuf },
+}
+
+// Polylines in pixel coordinates. Strokes flow along the curves.
+#[derive(Clone, Debug)]
+pub struct GuideCurves {
+    pub path: PathBuf,
+    pub curves: Vec<Vec<Point2<f32>>>,
+}
+
+impl GuideCurves {
+    // Reads polyline, polygon, line and path from SVG, and [[[x, y], ...], ...] from JSON.
+    pub fn load(path: &Path) -> Result<Self, GuideCurveError> {
+        let text = fs::read_to_string(path)?;
+        let curves = match path.extension().and_then(|e| e.to_str()) {
+            Some("svg") => parse_svg(&text, path)?,
+            Some("json") => serde_json::from_str::<Vec<Vec<[f32; 2]>>>(&text)?
+                .into_iter()
+                .map(|curve| curve.iter().map(|p| Point2::new(p[0], p[1])).collect())
+                .collect(),
+            _ => {
+                return Err(GuideCurveError::UnsupportedFormat { path: path.into() });
+            }
+        };
+        Ok(Self {
+            path: path.into(),
+            curves: curves.into_iter().filter(|c| c.len() >= 2).collect(),
+        })
+    }
+
+    // Put the doubled-angle tangent vector on the pixels the curves pass through.
+    fn rasterize(&self, width: u32, height: u32) -> (Vec<Vector2<f32>>, Vec<bool>) {
+        let (w, h) = (width as i32, height as i32);
+        let mut sums = vec![Vector2::zeros(); (width * height) as usize];
+        for curve in &self.curves {
+            for (a, b) in curve.iter().zip(curve.iter().skip(1)) {
+                let length = na::distance(a, b);
+                if length <= 0.0 {
+                    continue;
+                }
+                let tangent = double_angle(&((b - a) / length));
+                let steps = (length / RASTER_STEP).ceil() as usize;
+                for i in 0..=steps {
+                    let p = a + (b - a) * (i as f32 / steps as f32);
+                    let (x, y) = (p.x.round() as i32, p.y.round() as i32);
+                    if 0 <= x && x < w && 0 <= y && y < h {
+                        sums[(y * w + x) as usize] += tangent;
+                    }
+                }
+            }
+        }
+
+        // Pixels where opposite curves cancel out are left unconstrained.
+        let fixed = sums.iter().map(|s| s.norm() > 0.0).collect::<Vec<_>>();
+        let values = sums
+            .iter()
+            .map(|s| if s.norm() > 0.0 { s.normalize() } else { *s })
+            .collect();
+        (values, fixed)
+    }
+
+    // Fix the orientation on the curves and interpolate the rest by diffusion. The length of the
+    // interpolated vector tells how well the nearby curves agree, so it becomes the confidence.
+    pub fn direction_field(
+        &self,
+        width: u32,
+        height: u32,
+    ) -> Result<DirectionField, GuideCurveError> {
+        let (values, fixed) = self.rasterize(width, height);
+        if !fixed.iter().any(|&f| f) {
+            return Err(GuideCurveError::Empty {
+                path: self.path.clone(),
+            });
+        }
+        let doubled = diffuse(&values, &fixed, width as usize, height as usize);
+
+        let directions = doubled
+            .iter()
+            .map(|v| {
+                if v.norm() > 0.0 {
+                    half_angle(v)
+                } else {
+                    Vector2::zeros()
+                }
+            })
+            .collect();
+        let mut field = DirectionField::new(width, height, directions);
+        field.confidence = doubled.iter().map(|v| v.norm().min(1.0)).collect();
+        Ok(field)
+    }
+
+    // Weight that decreases away from the curves, 1.0 on them.
+    pub fn falloff_weights(&self, width: u32, height: u32, falloff: f32) -> Vec<f32> {
+        let (_, fixed) = self.rasterize(width, height);
+        euclidean_distance_transform(&fixed, width, height)
+            .distances
+            .iter()
+            .map(|&d2| {
+                if falloff > 0.0 {
+                    (-(d2 as f32) / (2.0 * falloff * falloff)).exp()
+                } else if d2 == 0.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+}
+
+// Keep the fixed pixels and fill the rest with the mean of their 4 neighbors (Laplace's
+// equation by Jacobi iteration). A half-size solution is used as the initial value to
+// converge faster.
+fn diffuse(
+    values: &[Vector2<f32>],
+    fixed: &[bool],
+    width: usize,
+    height: usize,
+) -> Vec<Vector2<f32>> {
+    let (mut current, iterations) = if width * height > COARSEST_PIXELS && width > 1 && height > 1 {
+        let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+        let mut coarse_values = vec![Vector2::zeros(); cw * ch];
+        let mut coarse_fixed = vec![false; cw * ch];
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                if fixed[i] {
+                    let ci = (y / 2) * cw + x / 2;
+                    coarse_values[ci] += values[i];
+                    coarse_fixed[ci] = true;
+                }
+            }
+        }
+        for (v, f) in coarse_values.iter_mut().zip(coarse_fixed.iter_mut()) {
+            if v.norm() > 0.0 {
+                *v = v.normalize();
+            } else {
+                *f = false;
+            }
+        }
+        let coarse = diffuse(&coarse_values, &coarse_fixed, cw, ch);
+        let current = (0..width * height)
+            .map(|i| {
+                if fixed[i] {
+                    values[i]
+                } else {
+                    coarse[(i / width / 2) * cw + (i % width) / 2]
+                }
+            })
+            .collect::<Vec<_>>();
+        (current, REFINE_ITERATIONS)
+    } else {
+        (values.to_vec(), COARSEST_ITERATIONS)
+    };
+
+    for _ in 0..iterations {
+        current = (0..width * height)
+            .into_par_iter()
+            .map(|i| {
+                if fixed[i] {
+                    return values[i];
+                }
+                let (x, y) = (i % width, i / width);
+                let mut sum = Vector2::zeros();
+                let mut count = 0.0;
+                if x > 0 {
+                    sum += current[i - 1];
+                    count += 1.0;
+                }
+                if x + 1 < width {
+                    sum += current[i + 1];
+                    count += 1.0;
+                }
+                if y > 0 {
+                    sum += current[i - width];
+                    count += 1.0;
+                }
+                if y + 1 < height {
+                    sum += current[i + width];
+                    count += 1.0;
+                }
+                if count > 0.0 {
+                    sum / count
+                } else {
+                    current[i]
+                }
+            })
+            .collect();
+    }
+    current
+}
+
+// Return an attribute value. Only names preceded by whitespace match, so that `d` is not
+// confused with the tail of `id`.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut start = 0;
+    while let Some(pos) = tag[start..].find(name) {
+        let pos = start + pos;
+        let rest = tag[pos + name.len()..].trim_start();
+        let preceded = tag[..pos].chars().last().is_some_and(|c| c.is_whitespace());
+        if preceded && rest.starts_with('=') {
+            let rest = rest[1..].trim_start();
+            let quote = rest.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let value = &rest[1..];
+                return value.find(quote).map(|end| &value[..end]);
+            }
+        }
+        start = pos + name.len();
+    }
+    None
+}
+
+// Also reads numbers with omitted separators such as "10-5" or "1.5.5".
+fn numbers(s: &str) -> Vec<f32> {
+    let mut numbers = vec![];
+    let mut token = String::new();
+    let flush = |token: &mut String, numbers: &mut Vec<f32>| {
+        if let Ok(n) = token.parse() {
+            numbers.push(n);
+        }
+        token.clear();
+    };
+    for c in s.chars() {
+        let starts_new = match c {
+            '-' | '+' => !token.is_empty() && !token.ends_with(&['e', 'E'][..]),
+            '.' => token.contains('.') && !token.contains(&['e', 'E'][..]),
+            _ => false,
+        };
+        if starts_new {
+            flush(&mut token, &mut numbers);
+        }
+        if c.is_ascii_digit() || "+-.eE".contains(c) {
+            token.push(c);
+        } else {
+            flush(&mut token, &mut numbers);
+        }
+    }
+    flush(&mut token, &mut numbers);
+    numbers
+}
+
+fn pairs(numbers: &[f32]) -> Vec<Point2<f32>> {
+    numbers
+        .chunks_exact(2)
+        .map(|p| Point2::new(p[0], p[1]))
+        .collect()
+}
+
+fn bezier(points: &[Point2<f32>], t: f32) -> Point2<f32> {
+    let mut points = points.to_vec();
+    while points.len() > 1 {
+        points = points
+            .iter()
+            .zip(points.iter().skip(1))
+            .map(|(a, b)| a + (b - a) * t)
+            .collect();
+    }
+    points[0]
+}
+
+// Flatten the d attribute of a path. Arcs and smooth shorthand commands are not supported.
+fn parse_path(d: &str, path: &Path) -> Result<Vec<Vec<Point2<f32>>>, GuideCurveError> {
+    let mut commands = vec![];
+    for c in d.chars() {
+        if c.is_ascii_alphabetic() && c != 'e' && c != 'E' {
+            commands.push((c, String::new()));
+        } else if let Some((_, args)) = commands.last_mut() {
+            args.push(c);
+        }
+    }
+
+    let mut curves = vec![];
+    let mut curve: Vec<Point2<f32>> = vec![];
+    let mut current = Point2::origin();
+    let mut start = Point2::origin();
+    for (command, args) in commands {
+        let args = numbers(&args);
+        let relative = command.is_ascii_lowercase();
+        let offset = if relative {
+            current.coords
+        } else {
+            Vector2::zeros()
+        };
+        match command.to_ascii_uppercase() {
+            'M' => {
+                if curve.len() >= 2 {
+                    curves.push(curve);
+                }
+                curve = vec![];
+                for (i, p) in pairs(&args).into_iter().enumerate() {
+                    // Extra coordinates are treated as L, relative to the last point if lower case.
+                    current = if relative && i > 0 {
+                        current + p.coords
+                    } else {
+                        p + offset
+                    };
+                    curve.push(current);
+                }
+                start = curve.first().cloned().unwrap_or(current);
+            }
+            'L' => {
+                for p in pairs(&args) {
+                    current = if relative { current + p.coords } else { p };
+                    curve.push(current);
+                }
+            }
+            'H' => {
+                for x in args {
+                    current.x = if relative { current.x + x } else { x };
+                    curve.push(current);
+                }
+            }
+            'V' => {
+                for y in args {
+                    current.y = if relative { current.y + y } else { y };
+                    curve.push(current);
+                }
+            }
+            'C' | 'Q' => {
+                let n = if command.eq_ignore_ascii_case(&'C') {
+                    3
+                } else {
+                    2
+                };
+                for controls in pairs(&args).chunks_exact(n) {
+                    let mut points = vec![current];
+                    points.extend(controls.iter().map(|p| {
+                        if relative {
+                            current + p.coords
+                        } else {
+                            *p
+                        }
+                    }));
+                    for i in 1..=BEZIER_SEGMENTS {
+                        curve.push(bezier(&points, i as f32 / BEZIER_SEGMENTS as f32));
+                    }
+                    current = points[n];
+                }
+            }
+            'Z' => {
+                curve.push(start);
+                current = start;
+            }
+            _ => {
+                return Err(GuideCurveError::UnsupportedPathCommand {
+                    path: path.into(),
+                    command,
+                });
+            }
+        }
+    }
+    if curve.len() >= 2 {
+        curves.push(curve);
+    }
+    Ok(curves)
+}
+
+// SVG coordinates are taken as pixels. viewBox and transform are ignored.
+fn parse_svg(text: &str, path: &Path) -> Result<Vec<Vec<Point2<f32>>>, GuideCurveError> {
+    let mut curves = vec![];
+    for tag in text.split('<').skip(1) {
+        let tag = tag.split('>').next().unwrap_or("");
+        let name = tag.split_whitespace().next().unwrap_or("");
+        match name {
+            "polyline" | "polygon" => {
+                if let Some(points) = attribute(tag, "points") {
+                    let mut curve = pairs(&numbers(points));
+                    if name == "polygon" {
+                        if let Some(&first) = curve.first() {
+                            curve.push(first);
+                        }
+                    }
+                    curves.push(curve);
+                }
+            }
+            "line" => {
+                let coordinate = |name| attribute(tag, name).and_then(|v| v.trim().parse().ok());
+                if let (Some(x1), Some(y1), Some(x2), Some(y2)) = (
+                    coordinate("x1"),
+                    coordinate("y1"),
+                    coordinate("x2"),
+                    coordinate("y2"),
+                ) {
+                    curves.push(vec![Point2::new(x1, y1), Point2::new(x2, y2)]);
+                }
+            }
+            "path" => {
+                if let Some(d) = attribute(tag, "d") {
+                    curves.extend(parse_path(d, path)?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(curves)
+}
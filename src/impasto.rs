@@ -0,0 +1,74 @@
+use anyhow::Result;
+use image::{ImageBuffer, Luma, Rgb};
+use na::Vector3;
+use nalgebra as na;
+use rayon::prelude::*;
+
+use crate::config::ImpastoConfig;
+
+fn with_suffix(output_path: &str, suffix: &str) -> String {
+    match output_path.rfind('.') {
+        Some(i) if !output_path[i..].contains('/') => {
+            output_path[..i].to_string() + "." + suffix + &output_path[i..]
+        }
+        _ => output_path.to_string() + "." + suffix + ".png",
+    }
+}
+
+// heights and colors run from the top row like the image.
+fn normals(heights: &[f32], width: usize, height: usize, strength: f32) -> Vec<Vector3<f32>> {
+    (0..(width * height))
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let h = |x: usize, y: usize| heights[y * width + x];
+            let dx = h((x + 1).min(width - 1), y) - h(x.saturating_sub(1), y);
+            // Image y points down, so flip the sign to make the normal map y point up.
+            let dy = h(x, y.saturating_sub(1)) - h(x, (y + 1).min(height - 1));
+            Vector3::new(-dx * strength * 0.5, -dy * strength * 0.5, 1.0).normalize()
+        })
+        .collect()
+}
+
+pub fn save_impasto(
+    heights: &[f32],
+    colors: &[Rgb<u8>],
+    width: usize,
+    height: usize,
+    config: &ImpastoConfig,
+    output_path: &str,
+) -> Result<()> {
+    let max_height = heights.iter().cloned().fold(0.0_f32, f32::max).max(1e-6);
+    let height_image = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let h = heights[y as usize * width + x as usize] / max_height;
+        Luma([(h * 65535.0).round() as u16])
+    });
+    let height_path = with_suffix(output_path, "height");
+    height_image.save(&height_path)?;
+
+    let normals = normals(heights, width, height, config.normal_strength);
+    let normal_image = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let n = normals[y as usize * width + x as usize];
+        let n = (n + Vector3::new(1.0, 1.0, 1.0)) * 0.5 * 255.0;
+        Rgb([n.x.round() as u8, n.y.round() as u8, n.z.round() as u8])
+    });
+    normal_image.save(with_suffix(output_path, "normal"))?;
+
+    if config.lit {
+        let light = Vector3::from(config.light_direction).normalize();
+        let half = (light + Vector3::z()).normalize();
+        let lit_image = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+            let i = y as usize * width + x as usize;
+            let n = normals[i];
+            let diffuse = n.dot(&light).max(0.0);
+            let specular = n.dot(&half).max(0.0).powf(config.shininess) * config.specular;
+            let shade = config.ambient + (1.0 - config.ambient) * diffuse;
+            let c = colors[i];
+            let f = |c: u8| (c as f32 * shade + specular * 255.0).clamp(0.0, 255.0) as u8;
+            Rgb([f(c[0]), f(c[1]), f(c[2])])
+        });
+        lit_image.save(with_suffix(output_path, "lit"))?;
+    }
+
+    Ok(())
+}
@@ -2,31 +2,43 @@
 use delta_e::DE2000;
 use float_cmp::*;
 use lerp::Lerp;
-use na::{Point2, Rotation2, Vector2, Vector3, Vector4};
+use na::{Point2, Rotation2, Vector2, Vector4};
 use nalgebra as na;
-use rand::distributions::WeightedIndex;
 use rand::prelude::*;
 use rand_distr::Normal;
 use rayon::prelude::*;
 
+use crate::brush_texture;
+use crate::color_sampling;
+use crate::config::{BrushConfig, RegionConfig, RenderConfig, SeedingConfig};
+use crate::maps::{self, Maps};
+use crate::palette::Palette;
+use crate::seeding::sample_seeds;
+use crate::stroke_geometry::{self, StrokeVertex};
+use crate::thickness_profile::ThicknessProfile;
+
 #[derive(Clone)]
 pub struct Stroke {
     pub pos: Vector2<i32>,
     pub color: Vector4<u8>,
     pub hopping_point: Vec<Point2<f32>>,
     pub thickness: f32,
+    pub profile: ThicknessProfile,
+    pub texture: Option<usize>,
+    // With a palette, color is the palette color with this index.
+    pub palette_index: Option<usize>,
+    // Index into the config regions the seed lies in. Mutations use that region's brush.
+    pub region: Option<usize>,
     importance: f32,
 }
 
 impl Stroke {
     pub fn new(
         index: usize,
-        colors: &Vec<Vector3<u8>>,
-        directions: &Vec<Vector2<f32>>,
-        importance: &Vec<f32>,
-        width: i32,
-        #[allow(unused_variables)] height: i32,
-        stroke_thickness: f32,
+        maps: &Maps,
+        brush: &BrushConfig,
+        region: Option<usize>,
+        palette: Option<&Palette>,
     ) -> Self {
         const THICKNESS_MIN_MEAN: f32 = 4.0;
         const THICKNESS_MIN_VARIANCE: f32 = 2.0;
@@ -47,6 +59,11 @@ impl Stroke {
         const HOP_END_COLOR_DISTANCE_VARIANCE: f32 = 10.0;
         const HOP_END_COLOR_DISTANCE_MIN: f32 = 2.0;
 
+        const EDGE_CLIP_MIN_LENGTH: f32 = 1.0;
+
+        let (colors, directions, importance, width) =
+            (&maps.colors, &maps.directions, &maps.importance, maps.width);
+
         let mut rng = thread_rng();
 
         let pos = {
@@ -55,17 +72,16 @@ impl Stroke {
             Vector2::new(x, y)
         };
 
-        let color = {
-            let color = colors[index];
-            Vector4::new(color.x, color.y, color.z, 255)
-        };
+        // Whether to grow the stroke is decided by the seed pixel color, not the paint color.
+        let seed_color = colors[index];
+        let seed_label = maps.label(index);
 
         let thickness = {
             let t = (importance[index] as f32).powf(THICKNESS_T_POW);
             let thickness_mean = THICKNESS_MAX_MEAN.lerp(THICKNESS_MIN_MEAN, t);
             let thickness_variance = THICKNESS_MAX_VARIANCE.lerp(THICKNESS_MIN_VARIANCE, t);
             let normal = Normal::new(thickness_mean, thickness_variance).unwrap();
-            let thickness = normal.sample(&mut rng) * stroke_thickness;
+            let thickness = normal.sample(&mut rng) * brush.stroke_thickness;
             thickness.max(THICKNESS_MIN) as f32
         };
 
@@ -163,15 +179,35 @@ impl Stroke {
                     * thickness;
                 let hop_point = s_last + (Rotation2::new(angle_prev + theta) * y) * hop_length;
 
+                // Crossing a strong edge clips the hop just before the edge and stops.
+                if let Some(threshold) = brush.edge_threshold {
+                    if let Some(clipped) = maps.clip_at_edge(&s_last, &hop_point, threshold) {
+                        if na::distance(&s_last, &clipped) >= EDGE_CLIP_MIN_LENGTH {
+                            s.push(clipped);
+                        }
+                        return true;
+                    }
+                }
+
+                // With a label map, stop just before entering a region other than the seed's.
+                if let Some(label) = seed_label {
+                    if let Some(clipped) = maps.clip_at_region(&s_last, &hop_point, label) {
+                        if na::distance(&s_last, &clipped) >= EDGE_CLIP_MIN_LENGTH {
+                            s.push(clipped);
+                        }
+                        return true;
+                    }
+                }
+
                 let hop_index = (hop_point.coords.y.round() as i32 * width
                     + hop_point.coords.x.round() as i32) as usize;
                 let hop_color = if hop_index < colors.len() - 1 {
                     let hop_color = colors[hop_index];
                     [hop_color.x, hop_color.y, hop_color.z]
                 } else {
-                    [color.x, color.y, color.z]
+                    [seed_color.x, seed_color.y, seed_color.z]
                 };
-                let stroke_color = [color.x, color.y, color.z];
+                let stroke_color = [seed_color.x, seed_color.y, seed_color.z];
 
                 if DE2000::from_rgb(&stroke_color, &hop_color)
                     > hop_end_distance_normal
@@ -196,6 +232,24 @@ impl Stroke {
             s1.into_iter().skip(1).rev().chain(s0).collect()
         };
 
+        let sampled_color =
+            color_sampling::sample_color(index, &hopping_point, thickness, maps, brush);
+        let palette_index = palette.map(|p| p.nearest(&sampled_color));
+        let color = {
+            let color = match (palette, palette_index) {
+                (Some(p), Some(i)) => p.color(i),
+                _ => sampled_color,
+            };
+            Vector4::new(color.x, color.y, color.z, Self::sample_opacity(brush))
+        };
+
+        let profile = ThicknessProfile::new(brush);
+
+        let texture = match brush_texture::texture_count(brush) {
+            0 => None,
+            n => Some(rng.gen_range(0, n)),
+        };
+
         let importance = importance[index];
 
         Self {
@@ -203,113 +257,59 @@ impl Stroke {
             color,
             hopping_point,
             thickness,
+            profile,
+            texture,
+            palette_index,
+            region,
             importance,
         }
     }
 
-    pub fn vertices(&self) -> Vec<Vector2<f32>> {
-        // const CATMULL_ROM_SUBDIVISION: i32 = 1;
-
-        // let catmull_rom_vertices = {
-        //     let mut vertices: Vec<Point2<f32>> = vec![];
-        //     for i in 1..self.hopping_point.len() {
-        //         for t in 0..CATMULL_ROM_SUBDIVISION {
-        //             let t = t as f32 / CATMULL_ROM_SUBDIVISION as f32;
-        //             let x = if i == 1 {
-        //                 let p1 = &self.hopping_point[i - 1].coords;
-        //                 let p2 = &self.hopping_point[i].coords;
-        //                 let p3 = &self.hopping_point[i + 1].coords;
-        //                 0.5 * ((p1 - 2.0 * p2 + p3) * t * t
-        //                     + (-3.0 * p1 + 4.0 * p2 - p3) * t
-        //                     + 2.0 * p1)
-        //             } else if i == self.hopping_point.len() - 1 {
-        //                 let p0 = &self.hopping_point[i - 2].coords;
-        //                 let p1 = &self.hopping_point[i - 1].coords;
-        //                 let p2 = &self.hopping_point[i].coords;
-        //                 0.5 * ((p0 - 2.0 * p1 + p2) * t * t + (-p0 + p2) * t + 2.0 * p1)
-        //             } else {
-        //                 let p0 = &self.hopping_point[i - 2].coords;
-        //                 let p1 = &self.hopping_point[i - 1].coords;
-        //                 let p2 = &self.hopping_point[i].coords;
-        //                 let p3 = &self.hopping_point[i + 1].coords;
-        //                 0.5 * ((-p0 + 3.0 * p1 - 3.0 * p2 + p3) * t * t * t
-        //                     + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
-        //                     + (-p0 + p3) * t
-        //                     + 2.0 * p1)
-        //             };
-        //             vertices.push(Point2::new(x.x, x.y));
-        //         }
-        //     }
-        //     vertices
-        // };
-
-        // let vertices = {
-        //     let mut vertices: Vec<Vector2<f32>> = vec![];
-        //     for i in 1..catmull_rom_vertices.len() {
-        //         let p0 = if i == 1 {
-        //             &catmull_rom_vertices[0]
-        //         } else {
-        //             &catmull_rom_vertices[i - 2]
-        //         };
-        //         let p1 = &catmull_rom_vertices[i - 1];
-        //         let p2 = &catmull_rom_vertices[i];
-        //         let p3 = if i == catmull_rom_vertices.len() - 1 {
-        //             &catmull_rom_vertices[catmull_rom_vertices.len() - 1]
-        //         } else {
-        //             &catmull_rom_vertices[i + 1]
-        //         };
-        //         let d0 = (p2.coords - p0.coords).normalize();
-        //         let d0 = Vector2::new(d0.y, -d0.x).normalize();
-        //         let d1 = (p3.coords - p1.coords).normalize();
-        //         let d1 = Vector2::new(d1.y, -d1.x).normalize();
-        //         let v0 = p1.coords + d0 * self.thickness / 2.0;
-        //         let v1 = p1.coords - d0 * self.thickness / 2.0;
-        //         let v2 = p2.coords + d1 * self.thickness / 2.0;
-        //         let v3 = p2.coords - d1 * self.thickness / 2.0;
-        //         vertices.push(v0.clone());
-        //         vertices.push(v1.clone());
-        //         vertices.push(v2.clone());
-        //         vertices.push(v2);
-        //         vertices.push(v1);
-        //         vertices.push(v3);
-        //     }
-        //     vertices
-        // };
-
-        let vertices = {
-            let mut vertices: Vec<Vector2<f32>> = vec![];
-            for i in 1..self.hopping_point.len() {
-                let p0 = if i == 1 {
-                    &self.hopping_point[0]
-                } else {
-                    &self.hopping_point[i - 2]
-                };
-                let p1 = &self.hopping_point[i - 1];
-                let p2 = &self.hopping_point[i];
-                let p3 = if i == self.hopping_point.len() - 1 {
-                    &self.hopping_point[self.hopping_point.len() - 1]
+    fn sample_opacity(brush: &BrushConfig) -> u8 {
+        let opacity = if brush.opacity_variance > 0.0 {
+            let normal = Normal::new(brush.opacity_mean, brush.opacity_variance).unwrap();
+            normal.sample(&mut thread_rng())
+        } else {
+            brush.opacity_mean
+        };
+        let opacity = opacity.max(brush.opacity_min).min(1.0);
+        (opacity * 255.0).round() as u8
+    }
+
+    pub fn mutate_opacity(&mut self, brush: &BrushConfig) {
+        self.color.w = Self::sample_opacity(brush);
+    }
+
+    // Repaint with another palette color.
+    pub fn mutate_palette_index(&mut self, palette: &Palette) {
+        if palette.len() < 2 {
+            return;
+        }
+        let mut rng = thread_rng();
+        let index = match self.palette_index {
+            // Pick among the colors other than the current one.
+            Some(current) => {
+                let index = rng.gen_range(0, palette.len() - 1);
+                if index >= current {
+                    index + 1
                 } else {
-                    &self.hopping_point[i + 1]
-                };
-                let d0 = (p2.coords - p0.coords).normalize();
-                let d0 = Vector2::new(d0.y, -d0.x).normalize();
-                let d1 = (p3.coords - p1.coords).normalize();
-                let d1 = Vector2::new(d1.y, -d1.x).normalize();
-                let v0 = p1.coords + d0 * self.thickness / 2.0;
-                let v1 = p1.coords - d0 * self.thickness / 2.0;
-                let v2 = p2.coords + d1 * self.thickness / 2.0;
-                let v3 = p2.coords - d1 * self.thickness / 2.0;
-                vertices.push(v0.clone());
-                vertices.push(v1.clone());
-                vertices.push(v2.clone());
-                vertices.push(v2);
-                vertices.push(v1);
-                vertices.push(v3);
+                    index
+                }
             }
-            vertices
+            None => rng.gen_range(0, palette.len()),
         };
+        let color = palette.color(index);
+        self.color = Vector4::new(color.x, color.y, color.z, self.color.w);
+        self.palette_index = Some(index);
+    }
 
-        vertices
+    pub fn vertices(&self, config: &RenderConfig) -> Vec<StrokeVertex> {
+        let centerline = stroke_geometry::centerline(&self.hopping_point, config);
+        let thicknesses = stroke_geometry::arc_length_parameters(&centerline)
+            .into_iter()
+            .map(|u| self.thickness * self.profile.eval(u))
+            .collect::<Vec<_>>();
+        stroke_geometry::triangulate(&centerline, &thicknesses, config)
     }
 }
 
@@ -321,6 +321,15 @@ impl PartialEq for Stroke {
         if !approx_eq!(f32, self.thickness, other.thickness) {
             return false;
         }
+        if self.color.w != other.color.w {
+            return false;
+        }
+        if self.profile != other.profile || self.texture != other.texture {
+            return false;
+        }
+        if self.palette_index != other.palette_index || self.region != other.region {
+            return false;
+        }
         let eq_iter = self
             .hopping_point
             .iter()
@@ -343,67 +352,50 @@ pub struct Individual {
 }
 
 impl Individual {
+    // Place brush.stroke_num strokes outside the regions with their own stroke count, and that
+    // many strokes inside each such region.
     pub fn new(
-        colors: &Vec<Vector3<u8>>,
-        directions: &Vec<Vector2<f32>>,
-        importance: &Vec<f32>,
-        width: i32,
-        height: i32,
-        stroke_num: u32,
-        stroke_thickness: f32,
+        maps: &Maps,
+        brush: &BrushConfig,
+        seeding: &SeedingConfig,
+        regions: &[RegionConfig],
+        palette: Option<&Palette>,
     ) -> Self {
         // println!("[{}] new start", Local::now());
+        let region_labels = regions
+            .iter()
+            .map(|r| maps::pack_label(r.label))
+            .collect::<Vec<_>>();
+        let region_brushes = regions.iter().map(|r| r.brush(brush)).collect::<Vec<_>>();
+        let region_of = |index: usize| {
+            maps.label(index)
+                .and_then(|label| region_labels.iter().position(|&l| l == label))
+        };
 
-        let weighted_random_dist = WeightedIndex::new(importance).unwrap();
-        let uniform_random_dist =
-            WeightedIndex::new(importance.iter().map(|_| 1.0).collect::<Vec<_>>()).unwrap();
-        let mut rng = thread_rng();
-
-        let weighted_random_stroke_num = (stroke_num as f64 * 0.95) as i32;
-        let uniform_random_stroke_num = stroke_num as i32 - weighted_random_stroke_num;
+        // No seeds on transparent parts of the color map.
+        let mut seeds = sample_seeds(maps, seeding, brush.stroke_num, |i| {
+            maps.is_paintable(i) && region_of(i).is_none_or(|r| regions[r].stroke_num.is_none())
+        });
+        for (r, region) in regions.iter().enumerate() {
+            if let Some(stroke_num) = region.stroke_num {
+                let label = region_labels[r];
+                seeds.append(&mut sample_seeds(maps, seeding, stroke_num, |i| {
+                    maps.is_paintable(i) && maps.label(i) == Some(label)
+                }));
+            }
+        }
 
-        let mut importance_strokes = (0..weighted_random_stroke_num)
-            .map(|_| weighted_random_dist.sample(&mut rng))
-            .collect::<Vec<_>>()
-            .par_iter()
-            .map(|&index| {
-                let stroke = Stroke::new(
-                    index,
-                    &colors,
-                    &directions,
-                    &importance,
-                    width,
-                    height,
-                    stroke_thickness,
-                );
-                stroke
-            })
-            .collect::<Vec<_>>();
-        let mut uniform_strokes = (0..uniform_random_stroke_num)
-            .map(|_| uniform_random_dist.sample(&mut rng))
-            .collect::<Vec<_>>()
+        let mut strokes = seeds
             .par_iter()
             .map(|&index| {
-                let stroke = Stroke::new(
-                    index,
-                    &colors,
-                    &directions,
-                    &importance,
-                    width,
-                    height,
-                    stroke_thickness,
-                );
-                stroke
+                let region = region_of(index);
+                let brush = region.map_or(brush, |r| &region_brushes[r]);
+                Stroke::new(index, maps, brush, region, palette)
             })
             .collect::<Vec<_>>();
+        strokes.sort_unstable_by(|a, b| a.importance.partial_cmp(&b.importance).unwrap());
 
-        importance_strokes.append(&mut uniform_strokes);
-        importance_strokes
-            .sort_unstable_by(|a, b| a.importance.partial_cmp(&b.importance).unwrap());
-
-        Self {
-            strokes: importance_strokes,
-        }
+        Self { strokes }
     }
 
     pub fn distance(&self, other: &Self) -> i32 {
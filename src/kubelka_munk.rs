@@ -0,0 +1,307 @@
+use anyhow::Result;
+use c_str_macro::c_str;
+use na::{Vector2, Vector3, Vector4};
+use nalgebra as na;
+use rayon::prelude::*;
+
+use crate::color::srgb_to_linear;
+use crate::config::{ColorSpace, ConfigError, Pigment, RenderConfig};
+use crate::render_gl::{self, buffer};
+use crate::resources::Resources;
+
+// Pigment concentrations are stored in the 4 RGBA channels, so at most 4 pigments.
+pub const MAX_PIGMENTS: usize = 4;
+
+// LUT grid points per axis from color to concentrations, and the concentration search step.
+const LUT_SIZE: usize = 33;
+const CONCENTRATION_STEPS: usize = 16;
+
+// Reflectance of k and s mixed by concentration, for a layer thick enough to hide the ground.
+pub fn reflectance(concentration: &Vector4<f32>, pigments: &[Pigment]) -> Vector3<f32> {
+    let mut k = Vector3::zeros();
+    let mut s = Vector3::zeros();
+    for (i, pigment) in pigments.iter().enumerate() {
+        k += Vector3::from(pigment.k) * concentration[i];
+        s += Vector3::from(pigment.s) * concentration[i];
+    }
+    k.zip_map(&s, |k, s| {
+        let ks = k / s.max(1e-6);
+        1.0 + ks - (ks * ks + 2.0 * ks).sqrt()
+    })
+}
+
+// Enumerate concentration combinations summing to 1. Unused pigments get 0.
+fn concentrations(pigment_num: usize) -> Vec<Vector4<f32>> {
+    let n = CONCENTRATION_STEPS;
+    let mut result = vec![];
+    for a in 0..=n {
+        for b in 0..=(n - a) {
+            for c in 0..=(n - a - b) {
+                let v = [a, b, c, n - a - b - c];
+                if v[pigment_num..].iter().any(|&x| x != 0) {
+                    continue;
+                }
+                result.push(Vector4::from(v).map(|x| x as f32 / n as f32));
+            }
+        }
+    }
+    result
+}
+
+// Table from sRGB colors to the pigment concentrations with the closest reflectance.
+pub struct PigmentLut {
+    entries: Vec<Vector4<f32>>,
+}
+
+impl PigmentLut {
+    pub fn new(pigments: &[Pigment]) -> Self {
+        let candidates = concentrations(pigments.len())
+            .into_iter()
+            .map(|c| (c, reflectance(&c, pigments)))
+            .collect::<Vec<_>>();
+
+        let channel = |i: usize| srgb_to_linear(i as f32 / (LUT_SIZE - 1) as f32);
+        let entries = (0..(LUT_SIZE * LUT_SIZE * LUT_SIZE))
+            .into_par_iter()
+            .map(|i| {
+                let target = Vector3::new(
+                    channel(i % LUT_SIZE),
+                    channel(i / LUT_SIZE % LUT_SIZE),
+                    channel(i / LUT_SIZE / LUT_SIZE),
+                );
+                candidates
+                    .iter()
+                    .map(|(c, r)| (c, (r - target).norm_squared()))
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                    .map(|(c, _)| *c)
+                    .unwrap()
+            })
+            .collect();
+
+        Self { entries }
+    }
+
+    // Trilinear interpolation between grid points. The concentrations still sum to 1.
+    pub fn lookup(&self, color: &Vector4<u8>) -> Vector4<f32> {
+        let scale = (LUT_SIZE - 1) as f32 / 255.0;
+        let (x, y, z) = (
+            color.x as f32 * scale,
+            color.y as f32 * scale,
+            color.z as f32 * scale,
+        );
+        let (x0, y0, z0) = (x as usize, y as usize, z as usize);
+        let (x1, y1, z1) = (
+            (x0 + 1).min(LUT_SIZE - 1),
+            (y0 + 1).min(LUT_SIZE - 1),
+            (z0 + 1).min(LUT_SIZE - 1),
+        );
+        let (fx, fy, fz) = (x - x0 as f32, y - y0 as f32, z - z0 as f32);
+        let entry = |x: usize, y: usize, z: usize| self.entries[(z * LUT_SIZE + y) * LUT_SIZE + x];
+
+        let c00 = entry(x0, y0, z0) * (1.0 - fx) + entry(x1, y0, z0) * fx;
+        let c10 = entry(x0, y1, z0) * (1.0 - fx) + entry(x1, y1, z0) * fx;
+        let c01 = entry(x0, y0, z1) * (1.0 - fx) + entry(x1, y0, z1) * fx;
+        let c11 = entry(x0, y1, z1) * (1.0 - fx) + entry(x1, y1, z1) * fx;
+        let c0 = c00 * (1.0 - fy) + c10 * fy;
+        let c1 = c01 * (1.0 - fy) + c11 * fy;
+        c0 * (1.0 - fz) + c1 * fz
+    }
+}
+
+// Accumulates strokes as pigment concentrations in a separate buffer, then converts them back to
+// color with Kubelka-Munk and composites the result.
+pub struct PigmentCompositor {
+    program: render_gl::Program,
+    _vbo: buffer::ArrayBuffer,
+    vao: buffer::VertexArray,
+    lut: PigmentLut,
+    texture: gl::types::GLuint,
+    frame_buffer: gl::types::GLuint,
+    size: Vector2<i32>,
+    target_frame_buffer: gl::types::GLuint,
+}
+
+impl PigmentCompositor {
+    pub fn new(config: &RenderConfig, res: &Resources) -> Result<Self> {
+        if config.pigments.is_empty() {
+            return Err(ConfigError::NoPigments.into());
+        }
+        if config.pigments.len() > MAX_PIGMENTS {
+            return Err(ConfigError::TooManyPigments {
+                count: config.pigments.len(),
+                max: MAX_PIGMENTS,
+            }
+            .into());
+        }
+
+        let program = render_gl::Program::from_res(res, "shaders/kubelka_munk")?;
+
+        let vertices: Vec<Vector2<f32>> = vec![
+            Vector2::new(-1.0, -1.0),
+            Vector2::new(1.0, -1.0),
+            Vector2::new(-1.0, 1.0),
+            Vector2::new(-1.0, 1.0),
+            Vector2::new(1.0, -1.0),
+            Vector2::new(1.0, 1.0),
+        ];
+
+        let vbo = buffer::ArrayBuffer::new();
+        vbo.bind();
+        vbo.static_draw_data(&vertices);
+        vbo.unbind();
+
+        let vao = buffer::VertexArray::new();
+        vao.bind();
+        vbo.bind();
+        unsafe {
+            gl::EnableVertexAttribArray(0);
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+        }
+        vbo.unbind();
+        vao.unbind();
+
+        let mut k = [0.0_f32; MAX_PIGMENTS * 3];
+        let mut s = [1.0_f32; MAX_PIGMENTS * 3];
+        for (i, pigment) in config.pigments.iter().enumerate() {
+            k[i * 3..i * 3 + 3].copy_from_slice(&pigment.k);
+            s[i * 3..i * 3 + 3].copy_from_slice(&pigment.s);
+        }
+
+        program.set_used();
+        unsafe {
+            gl::Uniform3fv(
+                gl::GetUniformLocation(program.id(), c_str!("K").as_ptr()),
+                MAX_PIGMENTS as i32,
+                k.as_ptr(),
+            );
+            gl::Uniform3fv(
+                gl::GetUniformLocation(program.id(), c_str!("S").as_ptr()),
+                MAX_PIGMENTS as i32,
+                s.as_ptr(),
+            );
+            gl::Uniform1i(
+                gl::GetUniformLocation(program.id(), c_str!("EncodeSrgb").as_ptr()),
+                (config.color_space == ColorSpace::Srgb) as i32,
+            );
+            gl::Uniform1i(
+                gl::GetUniformLocation(program.id(), c_str!("Pigments").as_ptr()),
+                2,
+            );
+        }
+
+        Ok(Self {
+            program,
+            _vbo: vbo,
+            vao,
+            lut: PigmentLut::new(&config.pigments),
+            texture: 0,
+            frame_buffer: 0,
+            size: Vector2::zeros(),
+            target_frame_buffer: 0,
+        })
+    }
+
+    pub fn concentration(&self, color: &Vector4<u8>) -> Vector4<f32> {
+        self.lut.lookup(color)
+    }
+
+    // Colors are read back at gl_FragCoord, so the buffer only needs to cover the viewport.
+    fn reserve(&mut self, width: i32, height: i32) {
+        if width <= self.size.x && height <= self.size.y {
+            return;
+        }
+        let size = Vector2::new(width.max(self.size.x), height.max(self.size.y));
+        self.delete_buffer();
+        unsafe {
+            gl::GenTextures(1, &mut self.texture);
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA16F as i32,
+                size.x,
+                size.y,
+                0,
+                gl::RGBA,
+                gl::FLOAT,
+                std::ptr::null() as *const gl::types::GLvoid,
+            );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+
+            gl::GenFramebuffers(1, &mut self.frame_buffer);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.frame_buffer);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                self.texture,
+                0,
+            );
+        }
+        self.size = size;
+    }
+
+    fn delete_buffer(&mut self) {
+        if self.frame_buffer != 0 {
+            unsafe {
+                gl::DeleteFramebuffers(1, &self.frame_buffer);
+                gl::DeleteTextures(1, &self.texture);
+            }
+            self.frame_buffer = 0;
+            self.texture = 0;
+        }
+    }
+
+    // Remember the bound framebuffer as the target, then clear the concentration buffer and start
+    // drawing.
+    pub fn begin(&mut self) {
+        let mut target: gl::types::GLint = 0;
+        let mut viewport = [0 as gl::types::GLint; 4];
+        unsafe {
+            gl::GetIntegerv(gl::DRAW_FRAMEBUFFER_BINDING, &mut target);
+            gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr());
+        }
+        self.target_frame_buffer = target as gl::types::GLuint;
+        self.reserve(viewport[2], viewport[3]);
+        self.resume();
+        unsafe {
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+        }
+    }
+
+    // The concentrations sum to the coverage, so blend over with the coverage the shader writes to
+    // its second output.
+    pub fn resume(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.frame_buffer);
+            gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC1_ALPHA);
+        }
+    }
+
+    pub fn bind_target(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.target_frame_buffer);
+            gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+        }
+    }
+
+    // Convert the concentrations to color and composite over the target with premultiplied alpha.
+    pub fn resolve(&self) {
+        self.program.set_used();
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE2);
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+        }
+        self.vao.bind();
+        unsafe {
+            gl::DrawArrays(gl::TRIANGLES, 0, 6);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
+    }
+}
+
+impl Drop for PigmentCompositor {
+    fn drop(&mut self) {
+        self.delete_buffer();
+    }
+}
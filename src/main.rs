@@ -1,4 +1,5 @@
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use structopt::StructOpt;
@@ -6,18 +7,174 @@ use structopt::StructOpt;
 pub mod render_gl;
 pub mod resources;
 
+mod brush_texture;
+mod canvas;
+mod color;
+mod color_sampling;
+mod config;
 mod create_direction_map;
+mod create_importance_map;
 mod create_individual;
+mod direction_encoding;
+mod direction_field;
+mod distance_transform;
 mod genetic_algorithm;
+mod guide_curves;
+mod impasto;
 mod individual;
+mod kubelka_munk;
+mod maps;
+mod palette;
 mod renderer;
+mod seeding;
+mod stroke_geometry;
+mod thickness_profile;
 mod triangle;
 mod visualize_direction_map;
 
-use create_direction_map::{create_direction_map_from_edge, create_direction_map_from_normal};
+use config::{RunConfig, SeedingStrategy};
+use create_direction_map::{
+    create_direction_map_from_curves, create_direction_map_from_edge,
+    create_direction_map_from_image, create_direction_map_from_normal, smooth_direction_map,
+    CurveBlend,
+};
+use create_importance_map::{
+    create_importance_map, create_importance_map_from_residual, ImportanceOptions, ImportanceTerm,
+};
 use create_individual::create_individual;
+use direction_encoding::{DirectionEncoding, DirectionOutput};
+use direction_field::{Degenerate, DegenerateFill, DirectionMethod, Smoothing, SmoothingMethod};
 use genetic_algorithm::genetic_algorithm;
-use visualize_direction_map::visualize_direction_map;
+use visualize_direction_map::{
+    export_direction_map_visualization, visualize_direction_map, ExportOptions, VisualizationStyle,
+};
+
+#[derive(StructOpt, Debug)]
+struct SmoothingOpt {
+    #[structopt(
+        long,
+        possible_values = &["gaussian", "bilateral", "anisotropic"],
+        about = "smooth the direction map"
+    )]
+    smooth: Option<SmoothingMethod>,
+    #[structopt(long, default_value = "4", about = "smoothing kernel radius")]
+    smooth_radius: u32,
+    #[structopt(long, default_value = "1", about = "smoothing iterations")]
+    smooth_iterations: u32,
+    #[structopt(
+        long,
+        default_value = "20",
+        about = "color sigma of bilateral smoothing"
+    )]
+    color_sigma: f32,
+    #[structopt(parse(from_os_str), long, about = "guide image of bilateral smoothing")]
+    guide: Option<PathBuf>,
+}
+
+impl SmoothingOpt {
+    fn smoothing(self) -> Option<Smoothing> {
+        let method = self.smooth?;
+        Some(Smoothing {
+            method,
+            radius: self.smooth_radius,
+            iterations: self.smooth_iterations,
+            color_sigma: self.color_sigma,
+            guide: self.guide,
+        })
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct EncodingOpt {
+    #[structopt(
+        long,
+        possible_values = &["rg8", "rg16", "angle8", "angle16", "npy"],
+        about = "direction map encoding (defaults to npy for .npy outputs and rg8 otherwise)"
+    )]
+    encoding: Option<DirectionEncoding>,
+}
+
+impl EncodingOpt {
+    fn output(self, path: PathBuf) -> DirectionOutput {
+        let encoding = self
+            .encoding
+            .unwrap_or_else(|| DirectionEncoding::from_path(&path));
+        DirectionOutput { path, encoding }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct DegenerateOpt {
+    #[structopt(
+        long,
+        default_value = "propagate",
+        possible_values = &["propagate", "angle"],
+        about = "how to fill pixels whose direction is undefined"
+    )]
+    degenerate_fill: DegenerateFill,
+    #[structopt(
+        long,
+        default_value = "90",
+        about = "default direction angle in degrees (0 is right, 90 is down)"
+    )]
+    default_angle: f32,
+    #[structopt(parse(from_os_str), long, about = "output confidence mask path")]
+    confidence_output: Option<PathBuf>,
+}
+
+impl DegenerateOpt {
+    fn degenerate(self) -> Degenerate {
+        Degenerate {
+            fill: self.degenerate_fill,
+            default_angle: self.default_angle,
+            confidence_output: self.confidence_output,
+        }
+    }
+}
+
+// Options shared by create-individual and ga that override the run config.
+#[derive(StructOpt, Debug)]
+struct RunOpt {
+    #[structopt(parse(from_os_str), long, about = "run config file (toml or yaml)")]
+    config: Option<PathBuf>,
+    #[structopt(parse(from_os_str), short, long, about = "input color map")]
+    color_map: Option<PathBuf>,
+    #[structopt(parse(from_os_str), short, long, about = "input direction map")]
+    dir_map: Option<PathBuf>,
+    #[structopt(parse(from_os_str), short, long, about = "input importance map")]
+    importance_map: Option<PathBuf>,
+    #[structopt(parse(from_os_str), long, about = "input edge map")]
+    edge_map: Option<PathBuf>,
+    #[structopt(long, about = "edge strength that stops stroke growth")]
+    edge_threshold: Option<f32>,
+    #[structopt(parse(from_os_str), long, about = "label map that confines strokes")]
+    label_map: Option<PathBuf>,
+    #[structopt(long, about = "resample maps whose size differs from the color map")]
+    resize_to_color_map: bool,
+    #[structopt(parse(from_os_str), short, long, about = "output path")]
+    output_path: Option<PathBuf>,
+    #[structopt(long, about = "stroke thickness scale")]
+    stroke_thickness: Option<f32>,
+    #[structopt(
+        long,
+        possible_values = &["weighted", "poisson-disk", "jittered-grid", "error-driven"],
+        about = "how stroke seeds are placed"
+    )]
+    seeding: Option<SeedingStrategy>,
+    #[structopt(long, about = "ratio of seeds placed ignoring importance")]
+    uniform_ratio: Option<f32>,
+    #[structopt(
+        parse(from_os_str),
+        long,
+        about = "previous result used by error-driven seeding"
+    )]
+    error_reference: Option<PathBuf>,
+    #[structopt(parse(from_os_str), long, about = "palette file")]
+    palette: Option<PathBuf>,
+    #[structopt(long, about = "number of palette colors taken from the color map")]
+    palette_size: Option<usize>,
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "sbrga", about = "A stroke based rendering tool set.")]
 enum Sbrga {
@@ -27,6 +184,12 @@ enum Sbrga {
         input: PathBuf,
         #[structopt(parse(from_os_str), short, long, about = "output file path")]
         output: Option<PathBuf>,
+        #[structopt(flatten)]
+        degenerate: DegenerateOpt,
+        #[structopt(flatten)]
+        encoding: EncodingOpt,
+        #[structopt(flatten)]
+        smoothing: SmoothingOpt,
     },
     #[structopt(about = "create direction map from edge map")]
     CreateDirmapFromEdge {
@@ -34,119 +197,454 @@ enum Sbrga {
         input: PathBuf,
         #[structopt(parse(from_os_str), short, long, about = "output file path")]
         output: Option<PathBuf>,
+        #[structopt(flatten)]
+        degenerate: DegenerateOpt,
+        #[structopt(flatten)]
+        encoding: EncodingOpt,
+        #[structopt(flatten)]
+        smoothing: SmoothingOpt,
+    },
+    #[structopt(about = "create direction map from color image")]
+    CreateDirmapFromImage {
+        #[structopt(parse(from_os_str), about = "input color image path")]
+        input: PathBuf,
+        #[structopt(parse(from_os_str), short, long, about = "output file path")]
+        output: Option<PathBuf>,
+        #[structopt(
+            long,
+            default_value = "structure-tensor",
+            possible_values = &["structure-tensor", "etf"],
+            about = "direction estimation method"
+        )]
+        method: DirectionMethod,
+        #[structopt(long, default_value = "5", about = "kernel radius")]
+        radius: u32,
+        #[structopt(long, default_value = "3", about = "ETF iterations")]
+        iterations: u32,
+        #[structopt(flatten)]
+        degenerate: DegenerateOpt,
+        #[structopt(flatten)]
+        encoding: EncodingOpt,
+        #[structopt(flatten)]
+        smoothing: SmoothingOpt,
+    },
+    #[structopt(about = "create direction map from guide curves")]
+    CreateDirmapFromCurves {
+        #[structopt(parse(from_os_str), about = "input guide curves path (.svg or .json)")]
+        input: PathBuf,
+        #[structopt(parse(from_os_str), short, long, about = "output file path")]
+        output: Option<PathBuf>,
+        #[structopt(
+            parse(from_os_str),
+            long,
+            about = "image whose size the direction map takes"
+        )]
+        reference: Option<PathBuf>,
+        #[structopt(
+            parse(from_os_str),
+            long,
+            about = "direction map to blend the guide curves into"
+        )]
+        base: Option<PathBuf>,
+        #[structopt(
+            parse(from_os_str),
+            long,
+            requires = "base",
+            about = "weight map of the guide curves (white uses the curves)"
+        )]
+        weight_map: Option<PathBuf>,
+        #[structopt(
+            long,
+            default_value = "50",
+            about = "distance in pixels over which the guide curves fade into the base"
+        )]
+        falloff: f32,
+        #[structopt(flatten)]
+        degenerate: DegenerateOpt,
+        #[structopt(flatten)]
+        encoding: EncodingOpt,
+        #[structopt(flatten)]
+        smoothing: SmoothingOpt,
+    },
+    #[structopt(about = "smooth direction map")]
+    SmoothDirmap {
+        #[structopt(parse(from_os_str), about = "input direction map path")]
+        input: PathBuf,
+        #[structopt(parse(from_os_str), short, long, about = "output file path")]
+        output: Option<PathBuf>,
+        #[structopt(flatten)]
+        encoding: EncodingOpt,
+        #[structopt(flatten)]
+        smoothing: SmoothingOpt,
     },
     #[structopt(about = "visualize direction map")]
     VisualizeDirmap {
         #[structopt(parse(from_os_str), about = "input direction map path")]
         input: PathBuf,
+        #[structopt(
+            parse(from_os_str),
+            short,
+            long,
+            about = "write the visualization to an image instead of opening a window"
+        )]
+        output: Option<PathBuf>,
+        #[structopt(
+            long,
+            default_value = "lic",
+            possible_values = &["lic", "glyph"],
+            about = "visualization style of the exported image"
+        )]
+        style: VisualizationStyle,
+        #[structopt(
+            parse(from_os_str),
+            long,
+            about = "image to draw the visualization over (e.g. the color map)"
+        )]
+        background: Option<PathBuf>,
+        #[structopt(
+            long,
+            default_value = "100",
+            about = "number of glyphs per row and column"
+        )]
+        grid: u32,
+        #[structopt(long, default_value = "10", about = "glyph length in pixels")]
+        glyph_size: f32,
+        #[structopt(
+            long,
+            default_value = "20",
+            about = "streamline length in pixels on each side for LIC"
+        )]
+        lic_length: u32,
+    },
+    #[structopt(about = "create importance map from color image")]
+    CreateImportanceMap {
+        #[structopt(parse(from_os_str), about = "input color image path")]
+        input: PathBuf,
+        #[structopt(parse(from_os_str), short, long, about = "output file path")]
+        output: Option<PathBuf>,
+        #[structopt(
+            long = "method",
+            default_value = "gradient",
+            about = "importance method with an optional weight, e.g. saliency:0.5 \
+                     (gradient, contrast, saliency, edge-distance); can be repeated"
+        )]
+        methods: Vec<ImportanceTerm>,
+        #[structopt(long, default_value = "5", about = "local contrast radius")]
+        radius: u32,
+        #[structopt(
+            long,
+            default_value = "0.25",
+            about = "gradient magnitude treated as an edge by edge-distance"
+        )]
+        edge_threshold: f32,
+        #[structopt(
+            long,
+            default_value = "20",
+            about = "distance in pixels over which edge-distance falls off"
+        )]
+        falloff: f32,
+        #[structopt(
+            long,
+            default_value = "1.0",
+            about = "gamma applied after normalization"
+        )]
+        gamma: f32,
+    },
+    #[structopt(about = "create importance map from the color error of a rendered result")]
+    CreateImportanceMapFromResidual {
+        #[structopt(parse(from_os_str), about = "target color map path")]
+        color_map: PathBuf,
+        #[structopt(parse(from_os_str), about = "rendered result path")]
+        result: PathBuf,
+        #[structopt(parse(from_os_str), short, long, about = "output file path")]
+        output: Option<PathBuf>,
+        #[structopt(long, default_value = "8", about = "blur radius of the error")]
+        blur_radius: u32,
+        #[structopt(
+            long,
+            default_value = "1.0",
+            about = "gamma applied after normalization"
+        )]
+        gamma: f32,
     },
     #[structopt(about = "create an individual painting")]
     CreateIndividual {
-        #[structopt(parse(from_os_str), short, long, about = "input color map")]
-        color_map: PathBuf,
-        #[structopt(parse(from_os_str), short, long, about = "input direction map")]
-        dir_map: PathBuf,
-        #[structopt(parse(from_os_str), short, long, about = "input importance map")]
-        importance_map: PathBuf,
-        #[structopt(parse(from_os_str), short, long, about = "output path")]
-        output_path: PathBuf,
-        #[structopt(default_value = "10000", short, long, about = "number of strokes")]
-        stroke_num: u32,
-        #[structopt(default_value = "1.0", long, about = "stroke thickness scale")]
-        stroke_thickness: f32,
+        #[structopt(flatten)]
+        run: RunOpt,
+        #[structopt(short, long, about = "number of strokes")]
+        stroke_num: Option<u32>,
     },
     #[structopt(about = "genetic algorithm process")]
     GA {
-        #[structopt(parse(from_os_str), short, long, about = "input color map")]
-        color_map: PathBuf,
-        #[structopt(parse(from_os_str), short, long, about = "input direction map")]
-        dir_map: PathBuf,
-        #[structopt(parse(from_os_str), short, long, about = "input importance map")]
-        importance_map: PathBuf,
-        #[structopt(parse(from_os_str), short, long, about = "output path")]
-        output_path: PathBuf,
-        #[structopt(default_value = "10000", long, about = "number of strokes")]
-        stroke_num: u32,
-        #[structopt(default_value = "1.0", long, about = "stroke thickness scale")]
-        stroke_thickness: f32,
-        #[structopt(default_value = "250", short, long, about = "population size")]
-        population_size: u32,
-        #[structopt(default_value = "100", short, long, about = "generation number")]
-        generation: usize,
+        #[structopt(flatten)]
+        run: RunOpt,
+        #[structopt(long, about = "number of strokes")]
+        stroke_num: Option<u32>,
+        #[structopt(short, long, about = "population size")]
+        population_size: Option<u32>,
+        #[structopt(short, long, about = "generation number")]
+        generation: Option<usize>,
         #[structopt(short, long, about = "save generation")]
         save_generation: Vec<usize>,
         #[structopt(long, about = "save generation step")]
-        save_generation_step: usize,
+        save_generation_step: Option<usize>,
         #[structopt(long, about = "save file width")]
-        width: i32,
+        width: Option<i32>,
         #[structopt(long, about = "save file height")]
-        height: i32,
-        #[structopt(default_value = "50", long, about = "D value")]
-        d_value: i32,
+        height: Option<i32>,
+        #[structopt(long, about = "D value")]
+        d_value: Option<i32>,
         #[structopt(long, about = "save sequence file")]
         save_sequence: Option<usize>,
+        #[structopt(long, about = "preview window height")]
+        window_height: Option<u32>,
     },
 }
 
+// Build an output path like input.<suffix>.png from input.png.
+fn default_output(input: &Path, suffix: &str) -> PathBuf {
+    match input.extension() {
+        Some(ext) => {
+            let mut extension = OsString::from(suffix);
+            extension.push(".");
+            extension.push(ext);
+            input.with_extension(extension)
+        }
+        None => input.with_extension(suffix),
+    }
+}
+
+// Load the run config file, if any, and apply the command line overrides on top of it.
+fn load_run_config(run: RunOpt, stroke_num: Option<u32>) -> Result<RunConfig> {
+    let mut config = if let Some(path) = run.config {
+        RunConfig::from_file(&path)?
+    } else {
+        RunConfig::default()
+    };
+
+    if run.color_map.is_some() {
+        config.input.color_map = run.color_map;
+    }
+    if run.dir_map.is_some() {
+        config.input.dir_map = run.dir_map;
+    }
+    if run.importance_map.is_some() {
+        config.input.importance_map = run.importance_map;
+    }
+    if run.edge_map.is_some() {
+        config.input.edge_map = run.edge_map;
+    }
+    if run.label_map.is_some() {
+        config.input.label_map = run.label_map;
+    }
+    if run.resize_to_color_map {
+        config.input.resize_to_color_map = true;
+    }
+    if run.output_path.is_some() {
+        config.output.output_path = run.output_path;
+    }
+    if let Some(stroke_num) = stroke_num {
+        config.brush.stroke_num = stroke_num;
+    }
+    if let Some(stroke_thickness) = run.stroke_thickness {
+        config.brush.stroke_thickness = stroke_thickness;
+    }
+    if run.edge_threshold.is_some() {
+        config.brush.edge_threshold = run.edge_threshold;
+    }
+    if let Some(seeding) = run.seeding {
+        config.seeding.strategy = seeding;
+    }
+    if let Some(uniform_ratio) = run.uniform_ratio {
+        config.seeding.uniform_ratio = uniform_ratio;
+    }
+    if run.error_reference.is_some() {
+        config.seeding.error_reference = run.error_reference;
+    }
+    if run.palette.is_some() {
+        config.palette.file = run.palette;
+    }
+    if run.palette_size.is_some() {
+        config.palette.size = run.palette_size;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
 fn main() -> Result<()> {
     let opt = Sbrga::from_args();
 
     match opt {
-        Sbrga::CreateDirmapFromNormal { input, output } => {
-            println!("Normal map file: {}", input.to_str().unwrap());
-            let output = if let Some(o) = output {
-                o
-            } else {
-                if let Some(ext) = input.extension() {
-                    input.with_extension("dir.".to_string() + ext.to_str().unwrap())
-                } else {
-                    input.with_extension("dir")
-                }
-            };
-            println!(">> Output file: {}", output.to_str().unwrap());
-            create_direction_map_from_normal(input.to_str().unwrap(), output.to_str().unwrap());
+        Sbrga::CreateDirmapFromNormal {
+            input,
+            output,
+            degenerate,
+            encoding,
+            smoothing,
+        } => {
+            println!("Normal map file: {}", input.display());
+            let output = output.unwrap_or_else(|| default_output(&input, "dir"));
+            println!(">> Output file: {}", output.display());
+            let output = encoding.output(output);
+            create_direction_map_from_normal(
+                &input,
+                &output,
+                &degenerate.degenerate(),
+                smoothing.smoothing().as_ref(),
+            )?;
         }
-        Sbrga::CreateDirmapFromEdge { input, output } => {
-            println!("Edge map file: {}", input.to_str().unwrap());
-            let output = if let Some(o) = output {
-                o
+        Sbrga::CreateDirmapFromEdge {
+            input,
+            output,
+            degenerate,
+            encoding,
+            smoothing,
+        } => {
+            println!("Edge map file: {}", input.display());
+            let output = output.unwrap_or_else(|| default_output(&input, "dir"));
+            println!(">> Output file: {}", output.display());
+            let output = encoding.output(output);
+            create_direction_map_from_edge(
+                &input,
+                &output,
+                &degenerate.degenerate(),
+                smoothing.smoothing().as_ref(),
+            )?;
+        }
+        Sbrga::CreateDirmapFromImage {
+            input,
+            output,
+            method,
+            radius,
+            iterations,
+            degenerate,
+            encoding,
+            smoothing,
+        } => {
+            println!("Image file: {}", input.display());
+            let output = output.unwrap_or_else(|| default_output(&input, "dir"));
+            println!(">> Output file: {}", output.display());
+            let output = encoding.output(output);
+            create_direction_map_from_image(
+                &input,
+                &output,
+                method,
+                radius,
+                iterations,
+                &degenerate.degenerate(),
+                smoothing.smoothing().as_ref(),
+            )?;
+        }
+        Sbrga::CreateDirmapFromCurves {
+            input,
+            output,
+            reference,
+            base,
+            weight_map,
+            falloff,
+            degenerate,
+            encoding,
+            smoothing,
+        } => {
+            println!("Guide curves file: {}", input.display());
+            let output = output.unwrap_or_else(|| input.with_extension("dir.png"));
+            println!(">> Output file: {}", output.display());
+            let output = encoding.output(output);
+            let blend = base.map(|base| CurveBlend {
+                base,
+                weight_map,
+                falloff,
+            });
+            create_direction_map_from_curves(
+                &input,
+                &output,
+                reference.as_deref(),
+                blend.as_ref(),
+                &degenerate.degenerate(),
+                smoothing.smoothing().as_ref(),
+            )?;
+        }
+        Sbrga::SmoothDirmap {
+            input,
+            output,
+            encoding,
+            mut smoothing,
+        } => {
+            println!("Direction map file: {}", input.display());
+            let output = output.unwrap_or_else(|| default_output(&input, "smooth"));
+            println!(">> Output file: {}", output.display());
+            let output = encoding.output(output);
+            // Smooth with a Gaussian if --smooth is omitted.
+            smoothing.smooth = smoothing.smooth.or(Some(SmoothingMethod::Gaussian));
+            smooth_direction_map(&input, &output, &smoothing.smoothing().unwrap())?;
+        }
+        Sbrga::VisualizeDirmap {
+            input,
+            output,
+            style,
+            background,
+            grid,
+            glyph_size,
+            lic_length,
+        } => {
+            if let Some(output) = output {
+                println!("Direction map file: {}", input.display());
+                println!(">> Output file: {}", output.display());
+                let options = ExportOptions {
+                    style,
+                    background,
+                    grid,
+                    glyph_size,
+                    lic_length,
+                };
+                export_direction_map_visualization(&input, &output, &options)?;
             } else {
-                if let Some(ext) = input.extension() {
-                    input.with_extension("dir.".to_string() + ext.to_str().unwrap())
-                } else {
-                    input.with_extension("dir")
-                }
-            };
-            println!(">> Output file: {}", output.to_str().unwrap());
-            create_direction_map_from_edge(input.to_str().unwrap(), output.to_str().unwrap());
+                visualize_direction_map(&input, grid as i32, grid as i32, glyph_size)?;
+            }
         }
-        Sbrga::VisualizeDirmap { input } => {
-            visualize_direction_map(input.to_str().unwrap(), 100, 100)?;
+        Sbrga::CreateImportanceMap {
+            input,
+            output,
+            methods,
+            radius,
+            edge_threshold,
+            falloff,
+            gamma,
+        } => {
+            println!("Image file: {}", input.display());
+            let output = output.unwrap_or_else(|| default_output(&input, "importance"));
+            println!(">> Output file: {}", output.display());
+            let options = ImportanceOptions {
+                terms: methods,
+                radius,
+                edge_threshold,
+                falloff,
+                gamma,
+            };
+            create_importance_map(&input, &output, &options)?;
         }
-        Sbrga::CreateIndividual {
+        Sbrga::CreateImportanceMapFromResidual {
             color_map,
-            dir_map,
-            importance_map,
-            output_path,
-            stroke_num,
-            stroke_thickness,
+            result,
+            output,
+            blur_radius,
+            gamma,
         } => {
-            create_individual(
-                color_map.to_str().unwrap(),
-                dir_map.to_str().unwrap(),
-                importance_map.to_str().unwrap(),
-                output_path.to_str().unwrap(),
-                stroke_num,
-                stroke_thickness,
-            )?;
+            println!("Color map file: {}", color_map.display());
+            println!("Result file: {}", result.display());
+            let output = output.unwrap_or_else(|| default_output(&result, "importance"));
+            println!(">> Output file: {}", output.display());
+            create_importance_map_from_residual(&color_map, &result, &output, blur_radius, gamma)?;
+        }
+        Sbrga::CreateIndividual { run, stroke_num } => {
+            let config = load_run_config(run, stroke_num)?;
+
+            create_individual(&config)?;
         }
         Sbrga::GA {
-            color_map,
-            dir_map,
-            importance_map,
-            output_path,
+            run,
             stroke_num,
-            stroke_thickness,
             population_size,
             generation,
             save_generation,
@@ -155,22 +653,41 @@ fn main() -> Result<()> {
             height,
             d_value,
             save_sequence,
-        } => genetic_algorithm(
-            color_map.to_str().unwrap(),
-            dir_map.to_str().unwrap(),
-            importance_map.to_str().unwrap(),
-            output_path.to_str().unwrap(),
-            stroke_num,
-            stroke_thickness,
-            population_size,
-            generation,
-            save_generation,
-            save_generation_step,
-            width,
-            height,
-            d_value,
-            save_sequence,
-        )?,
+            window_height,
+        } => {
+            let mut config = load_run_config(run, stroke_num)?;
+
+            if let Some(population_size) = population_size {
+                config.ga.population_size = population_size;
+            }
+            if let Some(generation) = generation {
+                config.ga.generation = generation;
+            }
+            if let Some(d_value) = d_value {
+                config.ga.d_value = d_value;
+            }
+            if !save_generation.is_empty() {
+                config.output.save_generation = save_generation;
+            }
+            if save_generation_step.is_some() {
+                config.output.save_generation_step = save_generation_step;
+            }
+            if width.is_some() {
+                config.output.width = width;
+            }
+            if height.is_some() {
+                config.output.height = height;
+            }
+            if save_sequence.is_some() {
+                config.output.save_sequence = save_sequence;
+            }
+            if let Some(window_height) = window_height {
+                config.output.window_height = window_height;
+            }
+            config.validate()?;
+
+            genetic_algorithm(&config)?
+        }
     }
 
     Ok(())
@@ -0,0 +1,447 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use delta_e::DE2000;
+use image::imageops::FilterType;
+use image::{self, ColorType, DynamicImage, GenericImageView};
+use na::{Point2, Vector2, Vector3};
+use nalgebra as na;
+use thiserror::Error;
+
+use crate::config::RunConfig;
+use crate::direction_encoding::{read_directions, DirectionMapError};
+use crate::direction_field::{
+    double_angle, gradient_magnitude, half_angle, DegenerateFill, DirectionField,
+};
+
+#[derive(Error, Debug)]
+pub enum MapError {
+    #[error("the {name} map does not exist: {path}")]
+    Missing { name: &'static str, path: PathBuf },
+    #[error("failed to open the {name} map {path}: {source}")]
+    Open {
+        name: &'static str,
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    #[error(transparent)]
+    Direction(#[from] DirectionMapError),
+    #[error(
+        "the {name} map {path} is {width}x{height} but the color map is \
+         {expected_width}x{expected_height} (use --resize-to-color-map to resample it)"
+    )]
+    SizeMismatch {
+        name: &'static str,
+        path: PathBuf,
+        width: u32,
+        height: u32,
+        expected_width: u32,
+        expected_height: u32,
+    },
+    #[error(
+        "unsupported channel layout of the {name} map {path}: {layout:?} (expected grayscale or RGB)"
+    )]
+    UnsupportedLayout {
+        name: &'static str,
+        path: PathBuf,
+        layout: ColorType,
+    },
+    #[error("the importance map {path} has no positive pixel, so no stroke can be placed")]
+    ZeroImportance { path: PathBuf },
+    #[error(
+        "the importance map {path} has no positive pixel inside the opaque area of \
+         the color map"
+    )]
+    ZeroImportanceInMask { path: PathBuf },
+}
+
+// Input maps used to generate and score strokes. All run row by row from the top left.
+pub struct Maps {
+    pub width: i32,
+    pub height: i32,
+    pub colors: Vec<Vector3<u8>>,
+    pub directions: Vec<Vector2<f32>>,
+    pub importance: Vec<f32>,
+    // Edge strength that stops strokes, 0.0 to 1.0. Only built when edge_threshold is set.
+    pub edges: Option<Vec<f32>>,
+    // Opacity of the color map. Only kept when some pixel is transparent.
+    pub alpha: Option<Vec<f32>>,
+    // Label map colors packed as 0xRRGGBB. Pixels with the same value form a region.
+    pub labels: Option<Vec<u32>>,
+    // DE2000 between a rendered result and the color map, used by error-driven seeding.
+    pub residual: Option<Vec<f32>>,
+}
+
+// No strokes are seeded on pixels less opaque than this.
+pub const PAINTABLE_ALPHA: f32 = 0.5;
+
+fn open_map(name: &'static str, path: &Path) -> Result<DynamicImage, MapError> {
+    if !path.exists() {
+        return Err(MapError::Missing {
+            name,
+            path: path.into(),
+        });
+    }
+    image::open(path).map_err(|source| MapError::Open {
+        name,
+        path: path.into(),
+        source,
+    })
+}
+
+// Scalar maps are read as luminance, so hand-painted maps saved as RGB(A) work as well. Alpha is
+// ignored. Layouts that have no obvious luminance are rejected.
+fn open_gray_map(name: &'static str, path: &Path) -> Result<DynamicImage, MapError> {
+    let image = open_map(name, path)?;
+    match image.color() {
+        ColorType::L8
+        | ColorType::La8
+        | ColorType::L16
+        | ColorType::La16
+        | ColorType::Rgb8
+        | ColorType::Rgba8
+        | ColorType::Rgb16
+        | ColorType::Rgba16
+        | ColorType::Bgr8
+        | ColorType::Bgra8 => Ok(DynamicImage::ImageLuma8(image.to_luma())),
+        layout => Err(MapError::UnsupportedLayout {
+            name,
+            path: path.into(),
+            layout,
+        }),
+    }
+}
+
+// Fail if the size differs from the color map and resize is false. Returns true when resampling
+// is needed.
+fn check_size(
+    name: &'static str,
+    path: &Path,
+    size: (u32, u32),
+    expected: (u32, u32),
+    resize: bool,
+) -> Result<bool, MapError> {
+    if size == expected {
+        Ok(false)
+    } else if resize {
+        println!(
+            "resize the {} map from {}x{} to {}x{}",
+            name, size.0, size.1, expected.0, expected.1
+        );
+        Ok(true)
+    } else {
+        Err(MapError::SizeMismatch {
+            name,
+            path: path.into(),
+            width: size.0,
+            height: size.1,
+            expected_width: expected.0,
+            expected_height: expected.1,
+        })
+    }
+}
+
+// Bilinear resampling with pixel centers aligned.
+fn resize_bilinear(values: &[f32], size: (u32, u32), new_size: (u32, u32)) -> Vec<f32> {
+    let (w, h) = (size.0 as i32, size.1 as i32);
+    let at = |x: i32, y: i32| values[(y.max(0).min(h - 1) * w + x.max(0).min(w - 1)) as usize];
+    let (sx, sy) = (
+        size.0 as f32 / new_size.0 as f32,
+        size.1 as f32 / new_size.1 as f32,
+    );
+    (0..new_size.0 * new_size.1)
+        .map(|i| {
+            let x = ((i % new_size.0) as f32 + 0.5) * sx - 0.5;
+            let y = ((i / new_size.0) as f32 + 0.5) * sy - 0.5;
+            let (x0, y0) = (x.floor(), y.floor());
+            let (fx, fy) = (x - x0, y - y0);
+            let (x0, y0) = (x0 as i32, y0 as i32);
+            let top = at(x0, y0) * (1.0 - fx) + at(x0 + 1, y0) * fx;
+            let bottom = at(x0, y0 + 1) * (1.0 - fx) + at(x0 + 1, y0 + 1) * fx;
+            top * (1.0 - fy) + bottom * fy
+        })
+        .collect()
+}
+
+// Values that cannot be interpolated, such as labels, take the nearest pixel.
+fn resize_nearest<T: Copy>(values: &[T], size: (u32, u32), new_size: (u32, u32)) -> Vec<T> {
+    (0..new_size.0 * new_size.1)
+        .map(|i| {
+            let x = ((i % new_size.0) as u64 * size.0 as u64 / new_size.0 as u64) as u32;
+            let y = ((i / new_size.0) as u64 * size.1 as u64 / new_size.1 as u64) as u32;
+            values[(y * size.0 + x) as usize]
+        })
+        .collect()
+}
+
+pub fn pack_label(color: [u8; 3]) -> u32 {
+    (color[0] as u32) << 16 | (color[1] as u32) << 8 | color[2] as u32
+}
+
+// DE2000 between the color map and rendered colors in the same order
+pub fn residual<I: Iterator<Item = [u8; 3]>>(colors: &[Vector3<u8>], rendered: I) -> Vec<f32> {
+    colors
+        .iter()
+        .zip(rendered)
+        .map(|(c, r)| DE2000::from_rgb(&[c.x, c.y, c.z], &r))
+        .collect()
+}
+
+// Interpolate doubled angles so that d and -d do not cancel out. The directions are stretched as
+// well when the aspect ratio changes.
+fn resize_directions(
+    directions: &[Vector2<f32>],
+    size: (u32, u32),
+    new_size: (u32, u32),
+) -> Vec<Vector2<f32>> {
+    let scale = Vector2::new(
+        new_size.0 as f32 / size.0 as f32,
+        new_size.1 as f32 / size.1 as f32,
+    );
+    let scaled = directions
+        .iter()
+        .map(|d| {
+            let d = d.component_mul(&scale);
+            if d.norm() > 0.0 {
+                double_angle(&d.normalize())
+            } else {
+                Vector2::zeros()
+            }
+        })
+        .collect::<Vec<_>>();
+    let component = |k: usize| {
+        resize_bilinear(
+            &scaled.iter().map(|d| d[k]).collect::<Vec<_>>(),
+            size,
+            new_size,
+        )
+    };
+    let (xs, ys) = (component(0), component(1));
+    xs.iter()
+        .zip(ys.iter())
+        .map(|(&x, &y)| half_angle(&Vector2::new(x, y)))
+        .collect()
+}
+
+impl Maps {
+    pub fn load(config: &RunConfig) -> Result<Self> {
+        let resize = config.input.resize_to_color_map;
+        let color_map_path = config.color_map()?;
+        let dir_map_path = config.dir_map()?;
+        let importance_map_path = config.importance_map()?;
+
+        let color_map = open_map("color", color_map_path)?;
+        let size = color_map.dimensions();
+        let (width, height) = (size.0 as i32, size.1 as i32);
+
+        let colors = color_map
+            .pixels()
+            .map(|(_, _, p)| Vector3::new(p[0], p[1], p[2]))
+            .collect::<Vec<_>>();
+        let alpha = if color_map.color().has_alpha() {
+            let alpha = color_map
+                .pixels()
+                .map(|(_, _, p)| p[3] as f32 / 255.0)
+                .collect::<Vec<_>>();
+            Some(alpha).filter(|alpha| alpha.iter().any(|&a| a < 1.0))
+        } else {
+            None
+        };
+
+        let (dir_width, dir_height, directions) =
+            read_directions(dir_map_path).map_err(MapError::from)?;
+        // Pixels without a direction, such as zero vectors in a .npy, take the nearest valid one.
+        let mut field = DirectionField::new(dir_width, dir_height, directions);
+        field.fill_degenerate(DegenerateFill::Propagate, 0.0);
+        let directions = if check_size(
+            "direction",
+            dir_map_path,
+            (dir_width, dir_height),
+            size,
+            resize,
+        )? {
+            resize_directions(&field.directions, (dir_width, dir_height), size)
+        } else {
+            field.directions
+        };
+
+        let importance_map = open_gray_map("importance", importance_map_path)?;
+        let importance = importance_map
+            .pixels()
+            .map(|(_, _, p)| p[0] as f32 / 255.0)
+            .collect::<Vec<_>>();
+        let importance = if check_size(
+            "importance",
+            importance_map_path,
+            importance_map.dimensions(),
+            size,
+            resize,
+        )? {
+            resize_bilinear(&importance, importance_map.dimensions(), size)
+        } else {
+            importance
+        };
+        if !importance.iter().any(|&v| v > 0.0) {
+            return Err(MapError::ZeroImportance {
+                path: importance_map_path.into(),
+            }
+            .into());
+        }
+        if let Some(alpha) = &alpha {
+            if !importance
+                .iter()
+                .zip(alpha.iter())
+                .any(|(&v, &a)| v > 0.0 && a >= PAINTABLE_ALPHA)
+            {
+                return Err(MapError::ZeroImportanceInMask {
+                    path: importance_map_path.into(),
+                }
+                .into());
+            }
+        }
+
+        // Without an edge map, the color map gradient magnitude is the edge strength.
+        // Edges are also needed when only a region brush looks at them.
+        let needs_edges = config.brush.edge_threshold.is_some()
+            || config.regions.iter().any(|r| r.edge_threshold.is_some());
+        let edges = if !needs_edges {
+            None
+        } else if let Some(path) = &config.input.edge_map {
+            let edge_map = open_gray_map("edge", path)?;
+            let edges = edge_map
+                .to_luma()
+                .pixels()
+                .map(|p| p[0] as f32 / 255.0)
+                .collect::<Vec<_>>();
+            if check_size("edge", path, edge_map.dimensions(), size, resize)? {
+                Some(resize_bilinear(&edges, edge_map.dimensions(), size))
+            } else {
+                Some(edges)
+            }
+        } else {
+            Some(gradient_magnitude(&color_map))
+        };
+
+        let labels = match &config.input.label_map {
+            Some(path) => {
+                let label_map = open_map("label", path)?;
+                let labels = label_map
+                    .to_rgb()
+                    .pixels()
+                    .map(|p| pack_label([p[0], p[1], p[2]]))
+                    .collect::<Vec<_>>();
+                if check_size("label", path, label_map.dimensions(), size, resize)? {
+                    Some(resize_nearest(&labels, label_map.dimensions(), size))
+                } else {
+                    Some(labels)
+                }
+            }
+            None => None,
+        };
+
+        // The previous result may be at its saved size, so always resample when the size differs.
+        let residual = match &config.seeding.error_reference {
+            Some(path) => {
+                let reference = open_map("error reference", path)?;
+                let reference = if reference.dimensions() == size {
+                    reference.to_rgb()
+                } else {
+                    reference
+                        .resize_exact(size.0, size.1, FilterType::Triangle)
+                        .to_rgb()
+                };
+                Some(residual(
+                    &colors,
+                    reference.pixels().map(|p| [p[0], p[1], p[2]]),
+                ))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            width,
+            height,
+            colors,
+            directions,
+            importance,
+            edges,
+            alpha,
+            labels,
+            residual,
+        })
+    }
+
+    // No strokes on transparent pixels.
+    pub fn is_paintable(&self, index: usize) -> bool {
+        self.alpha
+            .as_ref()
+            .is_none_or(|alpha| alpha[index] >= PAINTABLE_ALPHA)
+    }
+
+    // Keeps transparent colors out of palettes and the like.
+    pub fn paintable_colors(&self) -> Vec<Vector3<u8>> {
+        self.colors
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| self.is_paintable(i))
+            .map(|(_, c)| *c)
+            .collect()
+    }
+
+    pub fn index(&self, p: &Point2<f32>) -> Option<usize> {
+        let (x, y) = (p.x.round() as i32, p.y.round() as i32);
+        if 0 <= x && x < self.width && 0 <= y && y < self.height {
+            Some((y * self.width + x) as usize)
+        } else {
+            None
+        }
+    }
+
+    // Point just before crossing an edge when going from a to b, or None if no edge is crossed.
+    pub fn clip_at_edge(
+        &self,
+        a: &Point2<f32>,
+        b: &Point2<f32>,
+        threshold: f32,
+    ) -> Option<Point2<f32>> {
+        let edges = self.edges.as_ref()?;
+        self.clip(a, b, |index| edges[index] > threshold)
+    }
+
+    pub fn label(&self, index: usize) -> Option<u32> {
+        self.labels.as_ref().map(|labels| labels[index])
+    }
+
+    // Point just before leaving the `label` region when going from a to b, or None if it stays.
+    pub fn clip_at_region(
+        &self,
+        a: &Point2<f32>,
+        b: &Point2<f32>,
+        label: u32,
+    ) -> Option<Point2<f32>> {
+        let labels = self.labels.as_ref()?;
+        self.clip(a, b, |index| labels[index] != label)
+    }
+
+    // Step from a to b one pixel at a time and return the point before the first pixel where `stop`
+    // is true. Points outside the image are not checked.
+    fn clip<F: Fn(usize) -> bool>(
+        &self,
+        a: &Point2<f32>,
+        b: &Point2<f32>,
+        stop: F,
+    ) -> Option<Point2<f32>> {
+        let steps = na::distance(a, b).ceil().max(1.0) as usize;
+        let mut last = *a;
+        for i in 1..=steps {
+            let p = a + (b - a) * (i as f32 / steps as f32);
+            if let Some(index) = self.index(&p) {
+                if stop(index) {
+                    return Some(last);
+                }
+            }
+            last = p;
+        }
+        None
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use delta_e::DE2000;
+use na::Vector3;
+use nalgebra as na;
+use rand::prelude::*;
+use rayon::prelude::*;
+use thiserror::Error;
+
+use crate::color::srgb_to_lab;
+use crate::config::PaletteConfig;
+use crate::individual::Individual;
+
+#[derive(Error, Debug)]
+pub enum PaletteError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid color at {path}:{line}")]
+    InvalidColor { path: PathBuf, line: usize },
+    #[error("no colors in palette: {path}")]
+    Empty { path: PathBuf },
+    #[error("no paintable colors in the color map to build a palette from")]
+    EmptyColorMap,
+}
+
+#[derive(Clone, Debug)]
+pub struct PaletteColor {
+    pub name: Option<String>,
+    pub color: Vector3<u8>,
+}
+
+// Paint colors available to strokes
+#[derive(Clone, Debug)]
+pub struct Palette {
+    pub colors: Vec<PaletteColor>,
+}
+
+impl Palette {
+    pub fn from_config(
+        config: &PaletteConfig,
+        colors: &[Vector3<u8>],
+    ) -> Result<Option<Self>, PaletteError> {
+        if let Some(path) = &config.file {
+            Self::load(path).map(Some)
+        } else if let Some(size) = config.size {
+            Self::from_color_map(colors, size, config.iterations, config.sample_num).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // One color per line, as `#rrggbb [name]` or `r g b [name]` like GIMP palettes.
+    // Empty lines and lines starting with `#` that are not colors are skipped as comments.
+    pub fn load(path: &Path) -> Result<Self, PaletteError> {
+        let text = fs::read_to_string(path)?;
+        let mut colors = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty()
+                || line == "GIMP Palette"
+                || line.starts_with("Name:")
+                || line.starts_with("Columns:")
+            {
+                continue;
+            }
+            let invalid = || PaletteError::InvalidColor {
+                path: path.into(),
+                line: i + 1,
+            };
+            let mut tokens = line.split_whitespace();
+            let first = tokens.next().unwrap();
+            let color = if let Some(hex) = first.strip_prefix('#') {
+                match parse_hex(hex) {
+                    Some(color) => color,
+                    None => continue,
+                }
+            } else {
+                let mut channel = |first: Option<&str>| {
+                    first
+                        .or_else(|| tokens.next())
+                        .and_then(|t| t.parse::<u8>().ok())
+                        .ok_or_else(invalid)
+                };
+                let r = channel(Some(first))?;
+                let g = channel(None)?;
+                let b = channel(None)?;
+                Vector3::new(r, g, b)
+            };
+            let name = tokens.collect::<Vec<_>>().join(" ");
+            colors.push(PaletteColor {
+                name: if name.is_empty() { None } else { Some(name) },
+                color,
+            });
+        }
+
+        if colors.is_empty() {
+            return Err(PaletteError::Empty { path: path.into() });
+        }
+        Ok(Self { colors })
+    }
+
+    // k-means of pixels sampled from the color map in L*a*b* to get `size` colors.
+    pub fn from_color_map(
+        colors: &[Vector3<u8>],
+        size: usize,
+        iterations: usize,
+        sample_num: usize,
+    ) -> Result<Self, PaletteError> {
+        if colors.is_empty() {
+            return Err(PaletteError::EmptyColorMap);
+        }
+        let mut rng = thread_rng();
+        let sample_colors = colors
+            .choose_multiple(&mut rng, sample_num.max(1).min(colors.len()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let samples = sample_colors.iter().map(srgb_to_lab).collect::<Vec<_>>();
+        let size = size.max(1).min(samples.len());
+
+        // Pick the initial centers with k-means++.
+        let mut centers = vec![*samples.choose(&mut rng).unwrap()];
+        while centers.len() < size {
+            let distances = samples
+                .iter()
+                .map(|s| nearest_center(&centers, s).1)
+                .collect::<Vec<_>>();
+            let next = match rand::distributions::WeightedIndex::new(&distances) {
+                Ok(dist) => samples[dist.sample(&mut rng)],
+                // If every pixel matches an existing center, no more can be added.
+                Err(_) => break,
+            };
+            centers.push(next);
+        }
+
+        for _ in 0..iterations {
+            let labels = samples
+                .par_iter()
+                .map(|s| nearest_center(&centers, s).0)
+                .collect::<Vec<_>>();
+            let mut sums = vec![(Vector3::<f32>::zeros(), 0); centers.len()];
+            for (s, &label) in samples.iter().zip(labels.iter()) {
+                sums[label].0 += s;
+                sums[label].1 += 1;
+            }
+            for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+                if count > 0 {
+                    *center = sum / count as f32;
+                }
+            }
+        }
+
+        // Centers are means, so snap each one to the nearest sample it was fitted on. Centers that
+        // snap to a color already in the palette are dropped.
+        let mut seen = HashSet::new();
+        let palette = centers
+            .iter()
+            .map(|center| {
+                let (color, _) = sample_colors
+                    .iter()
+                    .zip(samples.iter())
+                    .min_by(|(_, a), (_, b)| {
+                        (*a - center)
+                            .norm_squared()
+                            .partial_cmp(&(*b - center).norm_squared())
+                            .unwrap()
+                    })
+                    .unwrap();
+                *color
+            })
+            .filter(|color| seen.insert((color.x, color.y, color.z)))
+            .map(|color| PaletteColor { name: None, color })
+            .collect::<Vec<_>>();
+
+        Ok(Self { colors: palette })
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn color(&self, index: usize) -> Vector3<u8> {
+        self.colors[index].color
+    }
+
+    pub fn nearest(&self, color: &Vector3<u8>) -> usize {
+        let c = [color.x, color.y, color.z];
+        self.colors
+            .iter()
+            .map(|p| DE2000::from_rgb(&c, &[p.color.x, p.color.y, p.color.z]))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(i, _)| i)
+            .unwrap()
+    }
+
+    pub fn stroke_counts(&self, individual: &Individual) -> Vec<usize> {
+        let mut counts = vec![0; self.len()];
+        for stroke in &individual.strokes {
+            if let Some(index) = stroke.palette_index {
+                counts[index] += 1;
+            }
+        }
+        counts
+    }
+
+    // Print the stroke count per color and save it next to the output as `<output>.palette.txt`.
+    pub fn report(&self, individual: &Individual, output_path: &str) -> Result<(), PaletteError> {
+        let mut text = String::new();
+        for (p, count) in self.colors.iter().zip(self.stroke_counts(individual)) {
+            let line = format!(
+                "#{:02x}{:02x}{:02x} {:>8} {}",
+                p.color.x,
+                p.color.y,
+                p.color.z,
+                count,
+                p.name.as_deref().unwrap_or("")
+            );
+            println!("{}", line.trim_end());
+            text += line.trim_end();
+            text += "\n";
+        }
+        fs::write(output_path.to_string() + ".palette.txt", text)?;
+        Ok(())
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vector3<u8>> {
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Vector3::new(channel(0)?, channel(2)?, channel(4)?))
+}
+
+fn nearest_center(centers: &[Vector3<f32>], lab: &Vector3<f32>) -> (usize, f32) {
+    centers
+        .iter()
+        .map(|c| (c - lab).norm_squared())
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .unwrap()
+}
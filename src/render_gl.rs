@@ -8,3 +8,6 @@ mod color_buffer;
 pub use self::color_buffer::ColorBuffer;
 
 pub mod buffer;
+
+mod texture;
+pub use self::texture::{Texture, TextureArray};
@@ -7,10 +7,104 @@ use na::{Matrix4, Point3, Vector2, Vector3, Vector4};
 use nalgebra as na;
 use rayon::prelude::*;
 
-use crate::individual::Individual;
+use crate::brush_texture;
+use crate::canvas::Canvas;
+use crate::color::{linear_to_srgb, srgb_to_linear};
+use crate::config::{BrushConfig, ColorSpace, Compositor, RenderConfig};
+use crate::impasto;
+use crate::individual::{Individual, Stroke};
+use crate::kubelka_munk::PigmentCompositor;
+use crate::maps;
 use crate::render_gl;
 use crate::resources::Resources;
 
+#[derive(Default)]
+struct StrokeMesh {
+    vertices: Vec<Vector2<f32>>,
+    colors: Vec<Vector4<f32>>,
+    uvs: Vec<Vector2<f32>>,
+    layers: Vec<f32>,
+    pigments: Vec<Vector4<f32>>,
+}
+
+impl StrokeMesh {
+    fn append(&mut self, other: &mut StrokeMesh) {
+        self.vertices.append(&mut other.vertices);
+        self.colors.append(&mut other.colors);
+        self.uvs.append(&mut other.uvs);
+        self.layers.append(&mut other.layers);
+        self.pigments.append(&mut other.pigments);
+    }
+
+    fn draw(&self) {
+        let vertices_vbo = render_gl::buffer::ArrayBuffer::new();
+        vertices_vbo.bind();
+        vertices_vbo.static_draw_data(&self.vertices);
+        vertices_vbo.unbind();
+
+        let colors_vbo = render_gl::buffer::ArrayBuffer::new();
+        colors_vbo.bind();
+        colors_vbo.static_draw_data(&self.colors);
+        colors_vbo.unbind();
+
+        let uvs_vbo = render_gl::buffer::ArrayBuffer::new();
+        uvs_vbo.bind();
+        uvs_vbo.static_draw_data(&self.uvs);
+        uvs_vbo.unbind();
+
+        let layers_vbo = render_gl::buffer::ArrayBuffer::new();
+        layers_vbo.bind();
+        layers_vbo.static_draw_data(&self.layers);
+        layers_vbo.unbind();
+
+        let pigments_vbo = render_gl::buffer::ArrayBuffer::new();
+        pigments_vbo.bind();
+        pigments_vbo.static_draw_data(&self.pigments);
+        pigments_vbo.unbind();
+
+        let vao = render_gl::buffer::VertexArray::new();
+        vao.bind();
+        vertices_vbo.bind();
+        unsafe {
+            gl::EnableVertexAttribArray(0);
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+        }
+        vertices_vbo.unbind();
+        colors_vbo.bind();
+        unsafe {
+            gl::EnableVertexAttribArray(1);
+            gl::VertexAttribPointer(1, 4, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+        }
+        colors_vbo.unbind();
+        uvs_vbo.bind();
+        unsafe {
+            gl::EnableVertexAttribArray(2);
+            gl::VertexAttribPointer(2, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+        }
+        uvs_vbo.unbind();
+        layers_vbo.bind();
+        unsafe {
+            gl::EnableVertexAttribArray(3);
+            gl::VertexAttribPointer(3, 1, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+        }
+        layers_vbo.unbind();
+        // Pigment concentrations are only needed for Kubelka-Munk compositing.
+        if !self.pigments.is_empty() {
+            pigments_vbo.bind();
+            unsafe {
+                gl::EnableVertexAttribArray(4);
+                gl::VertexAttribPointer(4, 4, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+            }
+            pigments_vbo.unbind();
+        }
+
+        // println!("[{}] draw arrays", Local::now());
+        unsafe {
+            gl::DrawArrays(gl::TRIANGLES, 0, self.vertices.len() as i32);
+        }
+    }
+}
+
 pub struct Renderer {
     width: i32,
     height: i32,
@@ -20,11 +114,18 @@ pub struct Renderer {
     v_height: i32,
     viewport: render_gl::Viewport,
     color_buffer: render_gl::ColorBuffer,
-    _shader_program: render_gl::Program,
+    shader_program: render_gl::Program,
+    height_program: render_gl::Program,
     render_texture: gl::types::GLuint,
     frame_buffer: gl::types::GLuint,
     save_image_render_texture: gl::types::GLuint,
     save_image_frame_buffer: gl::types::GLuint,
+    brush_textures: Option<render_gl::TextureArray>,
+    canvas: Option<Canvas>,
+    pigment_compositor: Option<PigmentCompositor>,
+    // Opacity of the color map, width x height from the top left.
+    alpha_mask: Option<Vec<f32>>,
+    config: RenderConfig,
 }
 
 impl Renderer {
@@ -33,10 +134,15 @@ impl Renderer {
         height: i32,
         save_image_width: i32,
         save_image_height: i32,
+        config: &RenderConfig,
+        brush: &BrushConfig,
         res: &Resources,
     ) -> Result<Self> {
         unsafe {
             gl::Enable(gl::MULTISAMPLE);
+            // The shader outputs premultiplied alpha, so this is over compositing.
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
         }
 
         let viewport = render_gl::Viewport::for_window(width as i32, height as i32);
@@ -47,9 +153,15 @@ impl Renderer {
 
         let shader_program = render_gl::Program::from_res(&res, "shaders/stroke")?;
         let view_projection_loc;
+        let brush_textures_loc;
+        let use_pigment_loc;
         unsafe {
             view_projection_loc =
                 gl::GetUniformLocation(shader_program.id(), c_str!("ViewProjection").as_ptr());
+            brush_textures_loc =
+                gl::GetUniformLocation(shader_program.id(), c_str!("BrushTextures").as_ptr());
+            use_pigment_loc =
+                gl::GetUniformLocation(shader_program.id(), c_str!("UsePigment").as_ptr());
         }
 
         let view_matrix = Matrix4::look_at_rh(
@@ -75,7 +187,46 @@ impl Renderer {
                 false as gl::types::GLboolean,
                 view_projection_matrix.as_ptr(),
             );
+            gl::Uniform1i(brush_textures_loc, 0);
+            gl::Uniform1i(
+                use_pigment_loc,
+                (config.compositor == Compositor::KubelkaMunk) as i32,
+            );
+        }
+
+        let height_program = render_gl::Program::from_res(res, "shaders/height")?;
+        height_program.set_used();
+        unsafe {
+            gl::UniformMatrix4fv(
+                gl::GetUniformLocation(height_program.id(), c_str!("ViewProjection").as_ptr()),
+                1 as gl::types::GLsizei,
+                false as gl::types::GLboolean,
+                view_projection_matrix.as_ptr(),
+            );
+            gl::Uniform1i(
+                gl::GetUniformLocation(height_program.id(), c_str!("BrushTextures").as_ptr()),
+                0,
+            );
+            gl::Uniform1f(
+                gl::GetUniformLocation(height_program.id(), c_str!("Height").as_ptr()),
+                config.impasto.height,
+            );
         }
+        shader_program.set_used();
+
+        let brush_textures = brush_texture::load_brush_textures(brush, res)?;
+        let canvas = Canvas::new(width, height, config, res)?;
+        let pigment_compositor = match config.compositor {
+            Compositor::Over => None,
+            Compositor::KubelkaMunk => Some(PigmentCompositor::new(config, res)?),
+        };
+        shader_program.set_used();
+
+        // Linear light compositing crushes the shadows in 8 bits, so use floating point.
+        let internal_format = match config.color_space {
+            ColorSpace::Srgb => gl::RGBA8,
+            ColorSpace::Linear => gl::RGBA16F,
+        };
 
         let mut render_texture: gl::types::GLuint = 0;
         let mut frame_buffer: gl::types::GLuint = 0;
@@ -85,12 +236,12 @@ impl Renderer {
             gl::TexImage2D(
                 gl::TEXTURE_2D,
                 0,
-                gl::RGBA8 as i32,
+                internal_format as i32,
                 width as i32,
                 height as i32,
                 0,
                 gl::RGBA,
-                gl::UNSIGNED_INT,
+                gl::FLOAT,
                 std::ptr::null() as *const gl::types::GLvoid,
             );
             gl::BindTexture(gl::TEXTURE_2D, 0);
@@ -116,12 +267,12 @@ impl Renderer {
             gl::TexImage2D(
                 gl::TEXTURE_2D,
                 0,
-                gl::RGBA8 as i32,
+                internal_format as i32,
                 save_image_width as i32,
                 save_image_height as i32,
                 0,
                 gl::RGBA,
-                gl::UNSIGNED_INT,
+                gl::FLOAT,
                 std::ptr::null() as *const gl::types::GLvoid,
             );
             gl::BindTexture(gl::TEXTURE_2D, 0);
@@ -148,79 +299,210 @@ impl Renderer {
             v_height: height,
             viewport,
             color_buffer,
-            _shader_program: shader_program,
+            shader_program,
+            height_program,
             render_texture,
             frame_buffer,
             save_image_render_texture,
             save_image_frame_buffer,
+            brush_textures,
+            canvas,
+            pigment_compositor,
+            alpha_mask: None,
+            config: config.clone(),
         })
     }
 
-    fn render(&self, individual: &Individual) {
-        self.color_buffer.clear();
+    fn stroke_mesh(&self, stroke: &Stroke) -> StrokeMesh {
+        let mut mesh = StrokeMesh::default();
+        let decode = |c: u8| match self.config.color_space {
+            ColorSpace::Srgb => c as f32 / 255.0,
+            ColorSpace::Linear => srgb_to_linear(c as f32 / 255.0),
+        };
+        let color = Vector4::new(
+            decode(stroke.color.x),
+            decode(stroke.color.y),
+            decode(stroke.color.z),
+            stroke.color.w as f32 / 255.0,
+        );
+        // Strokes without a texture get -1.
+        let layer = stroke.texture.map(|t| t as f32).unwrap_or(-1.0);
+        let pigment = self
+            .pigment_compositor
+            .as_ref()
+            .map(|c| c.concentration(&stroke.color));
+        for v in stroke.vertices(&self.config) {
+            mesh.vertices.push(Vector2::new(
+                v.position.x - self.width as f32 / 2.0,
+                (self.height as f32 - 1.0 - v.position.y) - self.height as f32 / 2.0,
+            ));
+            mesh.colors.push(color);
+            mesh.uvs.push(v.uv);
+            mesh.layers.push(layer);
+            if let Some(pigment) = pigment {
+                mesh.pigments.push(pigment);
+            }
+        }
+        mesh
+    }
+
+    fn read_pixels(&self, width: i32, height: i32) -> Vec<Vector4<f32>> {
+        let mut data = vec![Vector4::<f32>::zeros(); (width * height) as usize];
+        unsafe {
+            gl::ReadPixels(
+                0,
+                0,
+                width,
+                height,
+                gl::RGBA,
+                gl::FLOAT,
+                data.as_mut_ptr() as *mut gl::types::GLvoid,
+            );
+        }
+        data
+    }
+
+    fn encode(&self, c: f32) -> u8 {
+        let c = match self.config.color_space {
+            ColorSpace::Srgb => c,
+            ColorSpace::Linear => linear_to_srgb(c),
+        };
+        (c.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
 
+    // Keep premultiplied alpha and encode as 8 bits, as if composited over black.
+    fn to_premultiplied_rgba8(&self, p: &Vector4<f32>) -> Vector4<u8> {
+        Vector4::new(
+            self.encode(p.x),
+            self.encode(p.y),
+            self.encode(p.z),
+            (p.w.clamp(0.0, 1.0) * 255.0).round() as u8,
+        )
+    }
+
+    // The framebuffer holds premultiplied alpha, so undo it before saving an image.
+    fn to_straight_rgba8(&self, p: &Vector4<f32>) -> image::Rgba<u8> {
+        if p.w <= 0.0 {
+            return image::Rgba([0, 0, 0, 0]);
+        }
+        image::Rgba([
+            self.encode(p.x / p.w),
+            self.encode(p.y / p.w),
+            self.encode(p.z / p.w),
+            (p.w.min(1.0) * 255.0).round() as u8,
+        ])
+    }
+
+    // With a canvas the result is opaque, so save the composited color as is.
+    fn to_output_rgba8(&self, p: &Vector4<f32>) -> image::Rgba<u8> {
+        if self.canvas.is_some() {
+            image::Rgba([self.encode(p.x), self.encode(p.y), self.encode(p.z), 255])
+        } else {
+            self.to_straight_rgba8(p)
+        }
+    }
+
+    // Opacity of the color map at (x, y) of the saved image
+    fn mask_alpha(&self, x: u32, y: u32) -> f32 {
+        match &self.alpha_mask {
+            Some(mask) => {
+                let mx = (x as i64 * self.width as i64 / self.save_image_width as i64) as i32;
+                let my = (y as i64 * self.height as i64 / self.save_image_height as i64) as i32;
+                mask[(my * self.width + mx) as usize]
+            }
+            None => 1.0,
+        }
+    }
+
+    // The framebuffer runs bottom to top, so flip it into the image. Transparent parts of the color
+    // map stay transparent even with a canvas.
+    fn output_image(&self, data: &[Vector4<f32>]) -> image::RgbaImage {
+        image::ImageBuffer::from_fn(
+            self.save_image_width as u32,
+            self.save_image_height as u32,
+            |x, y| {
+                let p = data[((self.save_image_height - 1 - y as i32) * self.save_image_width
+                    + x as i32) as usize];
+                let mut pixel = self.to_output_rgba8(&p);
+                pixel[3] = (pixel[3] as f32 * self.mask_alpha(x, y)).round() as u8;
+                pixel
+            },
+        )
+    }
+
+    pub fn set_alpha_mask(&mut self, alpha_mask: Option<Vec<f32>>) {
+        self.alpha_mask = alpha_mask;
+    }
+
+    fn draw_canvas(&self) {
+        if let Some(canvas) = &self.canvas {
+            canvas.draw();
+            self.shader_program.set_used();
+        }
+    }
+
+    fn bind_brush_textures(&self) {
+        if let Some(brush_textures) = &self.brush_textures {
+            unsafe {
+                gl::ActiveTexture(gl::TEXTURE0);
+            }
+            brush_textures.bind();
+        }
+    }
+
+    fn individual_mesh(&self, individual: &Individual) -> StrokeMesh {
         // println!("[{}] create strokes", Local::now());
         let mut ss = individual
             .strokes
             .par_iter()
             // .iter()
-            .map(|stroke| {
-                let mut vertices = vec![];
-                let mut colors = vec![];
-                for v in stroke.vertices() {
-                    vertices.push(Vector2::new(
-                        v.x - self.width as f32 / 2.0,
-                        (self.height as f32 - 1.0 - v.y) - self.height as f32 / 2.0,
-                    ));
-                    colors.push(Vector4::new(
-                        stroke.color.x as f32 / 255.0,
-                        stroke.color.y as f32 / 255.0,
-                        stroke.color.z as f32 / 255.0,
-                        stroke.color.w as f32 / 255.0,
-                    ));
-                }
-                (vertices, colors)
-            })
+            .map(|stroke| self.stroke_mesh(stroke))
             .collect::<Vec<_>>();
-        let mut vertices = vec![];
-        let mut colors = vec![];
+        let mut mesh = StrokeMesh::default();
         for i in 0..ss.len() {
-            vertices.append(&mut ss[i].0);
-            colors.append(&mut ss[i].1);
+            mesh.append(&mut ss[i]);
         }
+        mesh
+    }
 
-        // println!("[{}] create vao", Local::now());
-        let vertices_vbo = render_gl::buffer::ArrayBuffer::new();
-        vertices_vbo.bind();
-        vertices_vbo.static_draw_data(&vertices);
-        vertices_vbo.unbind();
-
-        let colors_vbo = render_gl::buffer::ArrayBuffer::new();
-        colors_vbo.bind();
-        colors_vbo.static_draw_data(&colors);
-        colors_vbo.unbind();
-
-        let vao = render_gl::buffer::VertexArray::new();
-        vao.bind();
-        vertices_vbo.bind();
-        unsafe {
-            gl::EnableVertexAttribArray(0);
-            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+    // With Kubelka-Munk, strokes are drawn into the concentration buffer.
+    fn begin_strokes(&mut self) {
+        match &mut self.pigment_compositor {
+            Some(compositor) => compositor.begin(),
+            None => {
+                self.color_buffer.clear();
+                self.draw_canvas();
+            }
         }
-        vertices_vbo.unbind();
-        colors_vbo.bind();
-        unsafe {
-            gl::EnableVertexAttribArray(1);
-            gl::VertexAttribPointer(1, 4, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+    }
+
+    fn resume_strokes(&self) {
+        if let Some(compositor) = &self.pigment_compositor {
+            compositor.resume();
         }
-        colors_vbo.unbind();
+    }
 
-        // println!("[{}] draw arrays", Local::now());
-        unsafe {
-            gl::DrawArrays(gl::TRIANGLES, 0, vertices.len() as i32);
+    // Convert the concentration buffer back to color and composite it over the canvas.
+    fn end_strokes(&self) {
+        if let Some(compositor) = &self.pigment_compositor {
+            compositor.bind_target();
+            self.color_buffer.clear();
+            self.draw_canvas();
+            compositor.resolve();
+            self.shader_program.set_used();
         }
     }
 
+    fn render(&mut self, individual: &Individual) {
+        let mesh = self.individual_mesh(individual);
+
+        self.begin_strokes();
+        // println!("[{}] create vao", Local::now());
+        self.bind_brush_textures();
+        mesh.draw();
+        self.end_strokes();
+    }
+
     pub fn render_to_vec(&mut self, individual: &Individual) -> Vec<Vector4<u8>> {
         unsafe {
             gl::BindFramebuffer(gl::FRAMEBUFFER, self.frame_buffer);
@@ -233,21 +515,9 @@ impl Renderer {
         self.render(individual);
 
         // println!("[{}] read pixels", Local::now());
-        let mut data =
-            vec![Vector4::<gl::types::GLuint>::zeros(); (self.width * self.height) as usize];
-        unsafe {
-            gl::ReadPixels(
-                0,
-                0,
-                self.width as i32,
-                self.height as i32,
-                gl::RGBA,
-                gl::UNSIGNED_INT,
-                data.as_mut_ptr() as *mut gl::types::GLvoid,
-            );
-        }
-        data.into_iter()
-            .map(|c| Vector4::new(c.x as u8, c.y as u8, c.z as u8, c.w as u8))
+        self.read_pixels(self.width, self.height)
+            .iter()
+            .map(|c| self.to_premultiplied_rgba8(c))
             .collect()
     }
 
@@ -262,32 +532,108 @@ impl Renderer {
 
         self.render(individual);
 
-        let mut data = vec![
-            Vector4::<gl::types::GLuint>::zeros();
-            (self.save_image_width * self.save_image_height) as usize
-        ];
+        let data = self.read_pixels(self.save_image_width, self.save_image_height);
+        self.output_image(&data).save(output_path)?;
+
+        Ok(())
+    }
+
+    // Render a height map at the saved image size and save it with the normal map and others.
+    pub fn render_impasto_to_file(
+        &mut self,
+        individual: &Individual,
+        output_path: &str,
+    ) -> Result<()> {
+        let (width, height) = (self.save_image_width, self.save_image_height);
+
         unsafe {
-            gl::ReadPixels(
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.save_image_frame_buffer);
+        }
+        self.viewport.update_size(width, height);
+        self.viewport.set_used();
+        self.render(individual);
+        let colors = self.read_pixels(width, height);
+
+        let mut height_texture: gl::types::GLuint = 0;
+        let mut height_frame_buffer: gl::types::GLuint = 0;
+        unsafe {
+            gl::GenTextures(1, &mut height_texture);
+            gl::BindTexture(gl::TEXTURE_2D, height_texture);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
                 0,
+                gl::R32F as i32,
+                width,
+                height,
+                0,
+                gl::RED,
+                gl::FLOAT,
+                std::ptr::null() as *const gl::types::GLvoid,
+            );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+
+            gl::GenFramebuffers(1, &mut height_frame_buffer);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, height_frame_buffer);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                height_texture,
                 0,
-                self.save_image_width as i32,
-                self.save_image_height as i32,
-                gl::RGBA,
-                gl::UNSIGNED_INT,
-                data.as_mut_ptr() as *mut gl::types::GLvoid,
             );
         }
 
-        let mut imgbuf =
-            image::ImageBuffer::new(self.save_image_width as u32, self.save_image_height as u32);
-        for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-            let p = data[((self.save_image_height - 1 - y as i32) * self.save_image_width
-                + x as i32) as usize];
-            *pixel = image::Rgba([p.x as u8, p.y as u8, p.z as u8, p.w as u8]);
+        // Paint of overlapping strokes piles up, so blend additively.
+        self.color_buffer.clear();
+        let mesh = self.individual_mesh(individual);
+        self.height_program.set_used();
+        self.bind_brush_textures();
+        unsafe {
+            gl::BlendFunc(gl::ONE, gl::ONE);
         }
-        imgbuf.save(output_path)?;
+        mesh.draw();
+        unsafe {
+            gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+        }
+        self.shader_program.set_used();
 
-        Ok(())
+        let mut heights = vec![0.0_f32; (width * height) as usize];
+        unsafe {
+            gl::ReadPixels(
+                0,
+                0,
+                width,
+                height,
+                gl::RED,
+                gl::FLOAT,
+                heights.as_mut_ptr() as *mut gl::types::GLvoid,
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::DeleteFramebuffers(1, &height_frame_buffer);
+            gl::DeleteTextures(1, &height_texture);
+        }
+
+        // Pixels are read from the bottom row, so flip them.
+        let (width, height) = (width as usize, height as usize);
+        let flip = |i: usize| (height - 1 - i / width) * width + i % width;
+        let heights = (0..(width * height))
+            .map(|i| heights[flip(i)])
+            .collect::<Vec<_>>();
+        let colors = (0..(width * height))
+            .map(|i| {
+                let c = self.to_output_rgba8(&colors[flip(i)]);
+                image::Rgb([c[0], c[1], c[2]])
+            })
+            .collect::<Vec<_>>();
+
+        impasto::save_impasto(
+            &heights,
+            &colors,
+            width,
+            height,
+            &self.config.impasto,
+            output_path,
+        )
     }
 
     pub fn show(&mut self, individual: &Individual) {
@@ -296,7 +642,15 @@ impl Renderer {
         }
         self.viewport.update_size(self.v_width, self.v_height);
         self.viewport.set_used();
+        if self.config.color_space == ColorSpace::Linear {
+            unsafe {
+                gl::Enable(gl::FRAMEBUFFER_SRGB);
+            }
+        }
         self.render(individual);
+        unsafe {
+            gl::Disable(gl::FRAMEBUFFER_SRGB);
+        }
     }
 
     pub fn score(
@@ -323,14 +677,21 @@ impl Renderer {
                 let index = (y * self.width + x) as usize;
                 let v1 = colors[index];
                 let w = importance[index];
+                let a1 = self.alpha_mask.as_ref().map_or(1.0, |mask| mask[index]);
 
+                // With premultiplied alpha and no canvas, this compares colors over black.
+                // Transparent parts of the color map only check that they are left unpainted.
                 let c0 = [v0.x, v0.y, v0.z];
                 let c1 = [v1.x, v1.y, v1.z];
-                let color_loss = DE2000::from_rgb(&c0, &c1);
+                let color_loss = DE2000::from_rgb(&c0, &c1) * a1;
 
                 let a0 = v0.w as f32 / 255.0;
-                let a1 = 1.0;
-                let alpha_loss = (a0 - a1) * (a0 - a1) * 50.0;
+                let alpha_loss = if self.config.allow_uncovered {
+                    let over = (a0 - a1).max(0.0);
+                    over * over * 50.0
+                } else {
+                    (a0 - a1) * (a0 - a1) * 50.0
+                };
 
                 (color_loss + alpha_loss) * w
             })
@@ -339,6 +700,20 @@ impl Renderer {
         score
     }
 
+    // Render like score and return the color difference to the color map in color map order.
+    pub fn residual(&mut self, individual: &Individual, colors: &[Vector3<u8>]) -> Vec<f32> {
+        let data = self.render_to_vec(individual);
+        let (width, height) = (self.width, self.height);
+        maps::residual(
+            colors,
+            (0..colors.len()).map(|index| {
+                let (x, y) = (index as i32 % width, height - 1 - index as i32 / width);
+                let v = data[(y * width + x) as usize];
+                [v.x, v.y, v.z]
+            }),
+        )
+    }
+
     pub fn render_to_sequence_file(
         &mut self,
         individual: &Individual,
@@ -353,108 +728,34 @@ impl Renderer {
             .update_size(self.save_image_width, self.save_image_height);
         self.viewport.set_used();
 
-        self.color_buffer.clear();
+        self.begin_strokes();
 
-        let sss = individual
+        let mut sss = individual
             .strokes
             .par_iter()
             // .iter()
-            .map(|stroke| {
-                let mut vertices = vec![];
-                let mut colors = vec![];
-                for v in stroke.vertices() {
-                    vertices.push(Vector2::new(
-                        v.x - self.width as f32 / 2.0,
-                        (self.height as f32 - 1.0 - v.y) - self.height as f32 / 2.0,
-                    ));
-                    colors.push(Vector4::new(
-                        stroke.color.x as f32 / 255.0,
-                        stroke.color.y as f32 / 255.0,
-                        stroke.color.z as f32 / 255.0,
-                        stroke.color.w as f32 / 255.0,
-                    ));
-                }
-                (vertices, colors)
-            })
+            .map(|stroke| self.stroke_mesh(stroke))
             .collect::<Vec<_>>();
         let sss: Vec<_> = sss
-            .chunks(chunk_size)
+            .chunks_mut(chunk_size)
             .map(|slice| {
-                let mut vs = vec![];
-                let mut cs = vec![];
+                let mut mesh = StrokeMesh::default();
                 for item in slice {
-                    for v in &item.0 {
-                        vs.push(v.clone());
-                    }
-                    for c in &item.1 {
-                        cs.push(c.clone());
-                    }
+                    mesh.append(item);
                 }
-                (vs, cs)
+                mesh
             })
             .collect();
-        for (i, ss) in sss.into_iter().enumerate() {
-            let vertices = ss.0;
-            let colors = ss.1;
-
-            let vertices_vbo = render_gl::buffer::ArrayBuffer::new();
-            vertices_vbo.bind();
-            vertices_vbo.static_draw_data(&vertices);
-            vertices_vbo.unbind();
-
-            let colors_vbo = render_gl::buffer::ArrayBuffer::new();
-            colors_vbo.bind();
-            colors_vbo.static_draw_data(&colors);
-            colors_vbo.unbind();
-
-            let vao = render_gl::buffer::VertexArray::new();
-            vao.bind();
-            vertices_vbo.bind();
-            unsafe {
-                gl::EnableVertexAttribArray(0);
-                gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
-            }
-            vertices_vbo.unbind();
-            colors_vbo.bind();
-            unsafe {
-                gl::EnableVertexAttribArray(1);
-                gl::VertexAttribPointer(1, 4, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
-            }
-            colors_vbo.unbind();
+        self.bind_brush_textures();
+        for (i, mesh) in sss.into_iter().enumerate() {
+            self.resume_strokes();
+            mesh.draw();
+            self.end_strokes();
 
-            // println!("[{}] draw arrays", Local::now());
-            unsafe {
-                gl::DrawArrays(gl::TRIANGLES, 0, vertices.len() as i32);
-            }
-
-            let mut data = vec![
-                Vector4::<gl::types::GLuint>::zeros();
-                (self.save_image_width * self.save_image_height) as usize
-            ];
-            unsafe {
-                gl::ReadPixels(
-                    0,
-                    0,
-                    self.save_image_width as i32,
-                    self.save_image_height as i32,
-                    gl::RGBA,
-                    gl::UNSIGNED_INT,
-                    data.as_mut_ptr() as *mut gl::types::GLvoid,
-                );
-            }
-
-            let mut imgbuf = image::ImageBuffer::new(
-                self.save_image_width as u32,
-                self.save_image_height as u32,
-            );
-            for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-                let p = data[((self.save_image_height - 1 - y as i32) * self.save_image_width
-                    + x as i32) as usize];
-                *pixel = image::Rgba([p.x as u8, p.y as u8, p.z as u8, p.w as u8]);
-            }
+            let data = self.read_pixels(self.save_image_width, self.save_image_height);
 
             let output_path_with_i = output_path.to_string() + "/" + &i.to_string() + ".png";
-            imgbuf.save(output_path_with_i)?;
+            self.output_image(&data).save(output_path_with_i)?;
         }
 
         Ok(())
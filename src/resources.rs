@@ -9,6 +9,8 @@ use thiserror::Error;
 pub enum ResError {
     #[error(transparent)]
     Io(#[from] io::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
     #[error("file contains nil")]
     FileContainsNil,
     #[error("failed to get exe path")]
@@ -42,6 +44,13 @@ impl Resources {
 
         Ok(unsafe { ffi::CString::from_vec_unchecked(buffer) })
     }
+
+    pub fn load_image(&self, resource_name: &str) -> Result<image::DynamicImage, ResError> {
+        Ok(image::open(resource_name_to_path(
+            &self.root_path,
+            resource_name,
+        ))?)
+    }
 }
 
 fn resource_name_to_path(root_dir: &Path, location: &str) -> PathBuf {
@@ -0,0 +1,227 @@
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+
+use crate::config::{SeedingConfig, SeedingStrategy};
+use crate::maps::Maps;
+
+// Candidates tried by poisson-disk, as a multiple of the point count
+const POISSON_DISK_ATTEMPTS: u32 = 30;
+// poisson-disk spacing at the highest weight, as a fraction of min_distance
+const POISSON_DISK_DENSE_SCALE: f32 = 0.5;
+
+// Choose `count` pixels among the candidates for which `eligible` is true. Empty if there are none.
+pub fn sample_seeds<F: Fn(usize) -> bool>(
+    maps: &Maps,
+    config: &SeedingConfig,
+    count: u32,
+    eligible: F,
+) -> Vec<usize> {
+    let is_eligible = (0..maps.importance.len()).map(eligible).collect::<Vec<_>>();
+    let eligible_num = is_eligible.iter().filter(|&&e| e).count();
+    if eligible_num == 0 || count == 0 {
+        return Vec::new();
+    }
+
+    // error-driven weighs the color error by importance, like score does.
+    let weights = is_eligible
+        .iter()
+        .enumerate()
+        .map(|(i, &e)| match (e, config.strategy, &maps.residual) {
+            (false, _, _) => 0.0,
+            (true, SeedingStrategy::ErrorDriven, Some(residual)) => {
+                maps.importance[i] * residual[i]
+            }
+            (true, _, _) => maps.importance[i],
+        })
+        .collect::<Vec<_>>();
+    let uniform_ratio = config.uniform_ratio.clamp(0.0, 1.0);
+
+    match config.strategy {
+        SeedingStrategy::Weighted | SeedingStrategy::ErrorDriven => {
+            independent(&weights, &is_eligible, uniform_ratio, count)
+        }
+        SeedingStrategy::PoissonDisk => poisson_disk(
+            maps,
+            &weights,
+            &mix_uniform(&weights, &is_eligible, eligible_num, uniform_ratio),
+            eligible_num,
+            count,
+            config.min_distance,
+        ),
+        SeedingStrategy::JitteredGrid => jittered_grid(
+            maps,
+            &mix_uniform(&weights, &is_eligible, eligible_num, uniform_ratio),
+            eligible_num,
+            count,
+        ),
+    }
+}
+
+// Pick 1 - uniform_ratio of the seeds by weight and the rest uniformly, each independently.
+fn independent(
+    weights: &[f32],
+    is_eligible: &[bool],
+    uniform_ratio: f32,
+    count: u32,
+) -> Vec<usize> {
+    let uniform_random_dist =
+        WeightedIndex::new(is_eligible.iter().map(|&e| if e { 1.0 } else { 0.0 })).unwrap();
+    // If every candidate weight is 0, pick uniformly.
+    let weighted_random_dist =
+        WeightedIndex::new(weights).unwrap_or_else(|_| uniform_random_dist.clone());
+    let mut rng = thread_rng();
+
+    let weighted_random_stroke_num = (count as f64 * (1.0 - uniform_ratio as f64)) as u32;
+    let uniform_random_stroke_num = count - weighted_random_stroke_num;
+
+    let mut seeds = (0..weighted_random_stroke_num)
+        .map(|_| weighted_random_dist.sample(&mut rng))
+        .collect::<Vec<_>>();
+    seeds.extend((0..uniform_random_stroke_num).map(|_| uniform_random_dist.sample(&mut rng)));
+    seeds
+}
+
+// Mix a uniform distribution into the weights by uniform_ratio. The result sums to 1.0.
+fn mix_uniform(
+    weights: &[f32],
+    is_eligible: &[bool],
+    eligible_num: usize,
+    uniform_ratio: f32,
+) -> Vec<f32> {
+    let total: f32 = weights.iter().sum();
+    let weighted_ratio = if total > 0.0 {
+        1.0 - uniform_ratio
+    } else {
+        0.0
+    };
+    weights
+        .iter()
+        .zip(is_eligible.iter())
+        .map(|(&w, &e)| {
+            if !e {
+                0.0
+            } else if total > 0.0 {
+                weighted_ratio * w / total + (1.0 - weighted_ratio) / eligible_num as f32
+            } else {
+                1.0 / eligible_num as f32
+            }
+        })
+        .collect()
+}
+
+// Draw candidates by weight and reject those too close to an accepted point (dart throwing).
+// The spacing shrinks as the weight grows. If `count` is not reached within the attempt limit,
+// the rest are drawn without checking the spacing.
+fn poisson_disk(
+    maps: &Maps,
+    weights: &[f32],
+    mixed: &[f32],
+    eligible_num: usize,
+    count: u32,
+    min_distance: Option<f32>,
+) -> Vec<usize> {
+    let (width, height) = (maps.width, maps.height);
+    let dist = WeightedIndex::new(mixed).unwrap();
+    let mut rng = thread_rng();
+
+    let max_radius = min_distance
+        .unwrap_or_else(|| (eligible_num as f32 / count as f32).sqrt())
+        .max(f32::EPSILON);
+    let max_weight = weights.iter().cloned().fold(0.0, f32::max);
+    let radius = |index: usize| {
+        let t = if max_weight > 0.0 {
+            weights[index] / max_weight
+        } else {
+            0.0
+        };
+        max_radius * (1.0 - (1.0 - POISSON_DISK_DENSE_SCALE) * t)
+    };
+
+    // Register points in cells as wide as the smallest spacing; only check cells within max_radius.
+    let cell_size = (max_radius * POISSON_DISK_DENSE_SCALE).max(1.0);
+    let cols = (width as f32 / cell_size).ceil() as i32;
+    let rows = (height as f32 / cell_size).ceil() as i32;
+    let reach = (max_radius / cell_size).ceil() as i32;
+    let cell_of = |index: usize| {
+        let (x, y) = (index as i32 % width, index as i32 / width);
+        (
+            ((x as f32 / cell_size) as i32).min(cols - 1),
+            ((y as f32 / cell_size) as i32).min(rows - 1),
+        )
+    };
+    let mut grid = vec![Vec::<usize>::new(); (cols * rows) as usize];
+
+    let mut seeds = Vec::with_capacity(count as usize);
+    for _ in 0..count * POISSON_DISK_ATTEMPTS {
+        if seeds.len() >= count as usize {
+            break;
+        }
+        let candidate = dist.sample(&mut rng);
+        let (cx, cy) = cell_of(candidate);
+        let (x, y) = (candidate as i32 % width, candidate as i32 / width);
+        let r = radius(candidate);
+        let too_close = (cy - reach..=cy + reach)
+            .filter(|&gy| 0 <= gy && gy < rows)
+            .flat_map(|gy| {
+                (cx - reach..=cx + reach)
+                    .filter(|&gx| 0 <= gx && gx < cols)
+                    .map(move |gx| (gy * cols + gx) as usize)
+            })
+            .flat_map(|cell| grid[cell].iter())
+            .any(|&other| {
+                let (ox, oy) = (other as i32 % width, other as i32 / width);
+                let d = (((x - ox).pow(2) + (y - oy).pow(2)) as f32).sqrt();
+                d < (r + radius(other)) / 2.0
+            });
+        if !too_close {
+            grid[(cy * cols + cx) as usize].push(candidate);
+            seeds.push(candidate);
+        }
+    }
+    while seeds.len() < count as usize {
+        seeds.push(dist.sample(&mut rng));
+    }
+    seeds
+}
+
+// Split the image into about `count` cells and assign points to them by cutting the cumulative
+// weight at even steps. Positions inside a cell are jittered by weight.
+fn jittered_grid(maps: &Maps, mixed: &[f32], eligible_num: usize, count: u32) -> Vec<usize> {
+    let (width, height) = (maps.width, maps.height);
+    let mut rng = thread_rng();
+
+    let cell_size = ((eligible_num as f32 / count as f32).sqrt().round() as i32).max(1);
+    let cols = (width + cell_size - 1) / cell_size;
+    let rows = (height + cell_size - 1) / cell_size;
+    let mut cells = vec![Vec::<usize>::new(); (cols * rows) as usize];
+    for (index, &w) in mixed.iter().enumerate() {
+        if w > 0.0 {
+            let (x, y) = (index as i32 % width, index as i32 / width);
+            cells[((y / cell_size) * cols + x / cell_size) as usize].push(index);
+        }
+    }
+    let cell_weights = cells
+        .iter()
+        .map(|cell| cell.iter().map(|&i| mixed[i]).sum::<f32>())
+        .collect::<Vec<_>>();
+    let total: f32 = cell_weights.iter().sum();
+    let last = cells.iter().rposition(|cell| !cell.is_empty()).unwrap();
+
+    let step = total / count as f32;
+    let offset = rng.gen_range(0.0, step);
+    let mut seeds = Vec::with_capacity(count as usize);
+    let mut cell = 0;
+    let mut cumulative = 0.0;
+    for k in 0..count {
+        let target = offset + k as f32 * step;
+        // Skip cells of weight 0 and do not run past the last cell due to rounding.
+        while cell < last && cumulative + cell_weights[cell] <= target {
+            cumulative += cell_weights[cell];
+            cell += 1;
+        }
+        let pixels = &cells[cell];
+        let dist = WeightedIndex::new(pixels.iter().map(|&i| mixed[i])).unwrap();
+        seeds.push(pixels[dist.sample(&mut rng)]);
+    }
+    seeds
+}
@@ -0,0 +1,280 @@
+use lerp::Lerp;
+use na::{Point2, Rotation2, Vector2};
+use nalgebra as na;
+
+use crate::config::{CapType, JoinType, RenderConfig, SplineType};
+
+const MIN_SEGMENT_LENGTH: f32 = 1e-4;
+const ROUND_CAP_SEGMENTS: usize = 8;
+const TAPER_CAP_LENGTH: f32 = 2.0;
+
+// Remove duplicate points, since zero-length segments give NaN normals.
+fn dedup_points(points: &[Point2<f32>]) -> Vec<Point2<f32>> {
+    let mut result: Vec<Point2<f32>> = vec![];
+    for p in points {
+        match result.last() {
+            Some(last) if na::distance(last, p) < MIN_SEGMENT_LENGTH => {}
+            _ => result.push(*p),
+        }
+    }
+    result
+}
+
+// Catmull-Rom interpolation with the pyramidal formulation of Barry and Goldman.
+// alpha = 0.0 is uniform and alpha = 0.5 centripetal.
+fn catmull_rom(
+    p0: &Point2<f32>,
+    p1: &Point2<f32>,
+    p2: &Point2<f32>,
+    p3: &Point2<f32>,
+    alpha: f32,
+    t: f32,
+) -> Point2<f32> {
+    let knot =
+        |a: &Point2<f32>, b: &Point2<f32>| na::distance(a, b).max(MIN_SEGMENT_LENGTH).powf(alpha);
+    let t0 = 0.0;
+    let t1 = t0 + knot(p0, p1);
+    let t2 = t1 + knot(p1, p2);
+    let t3 = t2 + knot(p2, p3);
+    let t = t1.lerp(t2, t);
+
+    let a1 = p0.coords * ((t1 - t) / (t1 - t0)) + p1.coords * ((t - t0) / (t1 - t0));
+    let a2 = p1.coords * ((t2 - t) / (t2 - t1)) + p2.coords * ((t - t1) / (t2 - t1));
+    let a3 = p2.coords * ((t3 - t) / (t3 - t2)) + p3.coords * ((t - t2) / (t3 - t2));
+    let b1 = a1 * ((t2 - t) / (t2 - t0)) + a2 * ((t - t0) / (t2 - t0));
+    let b2 = a2 * ((t3 - t) / (t3 - t1)) + a3 * ((t - t1) / (t3 - t1));
+    Point2::from(b1 * ((t2 - t) / (t2 - t1)) + b2 * ((t - t1) / (t2 - t1)))
+}
+
+pub fn centerline(points: &[Point2<f32>], config: &RenderConfig) -> Vec<Point2<f32>> {
+    let points = dedup_points(points);
+    let alpha = match config.spline {
+        SplineType::Polyline => return points,
+        SplineType::CatmullRom => 0.0,
+        SplineType::Centripetal => 0.5,
+    };
+    if points.len() < 3 {
+        return points;
+    }
+
+    let n = points.len();
+    // The ends get virtual control points mirroring their neighbors.
+    let first = Point2::from(points[0].coords * 2.0 - points[1].coords);
+    let last = Point2::from(points[n - 1].coords * 2.0 - points[n - 2].coords);
+    let control = |i: isize| {
+        if i < 0 {
+            first
+        } else if i as usize >= n {
+            last
+        } else {
+            points[i as usize]
+        }
+    };
+
+    let subdivision = config.subdivision.max(1);
+    let mut result = vec![points[0]];
+    for i in 0..(n - 1) as isize {
+        let (p0, p1, p2, p3) = (control(i - 1), control(i), control(i + 1), control(i + 2));
+        for s in 1..=subdivision {
+            let t = s as f32 / subdivision as f32;
+            result.push(catmull_rom(&p0, &p1, &p2, &p3, alpha, t));
+        }
+    }
+    dedup_points(&result)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StrokeVertex {
+    pub position: Vector2<f32>,
+    // Texture coordinates: x along the stroke, y across it
+    pub uv: Vector2<f32>,
+}
+
+impl StrokeVertex {
+    fn new(position: Vector2<f32>, u: f32, v: f32) -> Self {
+        Self {
+            position,
+            uv: Vector2::new(u, v),
+        }
+    }
+}
+
+fn normal(d: &Vector2<f32>) -> Vector2<f32> {
+    Vector2::new(d.y, -d.x)
+}
+
+fn push_quad(
+    vertices: &mut Vec<StrokeVertex>,
+    v0: StrokeVertex,
+    v1: StrokeVertex,
+    v2: StrokeVertex,
+    v3: StrokeVertex,
+) {
+    vertices.push(v0);
+    vertices.push(v1);
+    vertices.push(v2);
+    vertices.push(v2);
+    vertices.push(v1);
+    vertices.push(v3);
+}
+
+// stroke_n is the normal of the stroke body, used to align the texture coordinate across it.
+fn push_cap(
+    vertices: &mut Vec<StrokeVertex>,
+    center: Vector2<f32>,
+    dir: Vector2<f32>,
+    stroke_n: Vector2<f32>,
+    u: f32,
+    half_width: f32,
+    config: &RenderConfig,
+) {
+    let n = normal(&dir);
+    let v = |a: &Vector2<f32>| 0.5 - 0.5 * a.dot(&stroke_n);
+    match config.cap {
+        CapType::Butt => {}
+        CapType::Round => {
+            // Fan a half circle from n through dir to -n.
+            let step = std::f32::consts::PI / ROUND_CAP_SEGMENTS as f32;
+            for i in 0..ROUND_CAP_SEGMENTS {
+                let a = Rotation2::new(step * i as f32) * n;
+                let b = Rotation2::new(step * (i + 1) as f32) * n;
+                vertices.push(StrokeVertex::new(center, u, 0.5));
+                vertices.push(StrokeVertex::new(center + a * half_width, u, v(&a)));
+                vertices.push(StrokeVertex::new(center + b * half_width, u, v(&b)));
+            }
+        }
+        CapType::Taper => {
+            vertices.push(StrokeVertex::new(center + n * half_width, u, v(&n)));
+            vertices.push(StrokeVertex::new(center - n * half_width, u, v(&-n)));
+            vertices.push(StrokeVertex::new(
+                center + dir * half_width * TAPER_CAP_LENGTH,
+                u,
+                0.5,
+            ));
+        }
+    }
+}
+
+// Arc length from the start to each centerline point, normalized to 0.0 to 1.0.
+pub fn arc_length_parameters(centerline: &[Point2<f32>]) -> Vec<f32> {
+    let mut lengths = vec![0.0];
+    for (a, b) in centerline.iter().zip(centerline.iter().skip(1)) {
+        lengths.push(lengths[lengths.len() - 1] + na::distance(a, b));
+    }
+    let total = lengths[lengths.len() - 1];
+    if total > 0.0 {
+        lengths.iter().map(|l| l / total).collect()
+    } else {
+        lengths
+    }
+}
+
+pub fn triangulate(
+    centerline: &[Point2<f32>],
+    thicknesses: &[f32],
+    config: &RenderConfig,
+) -> Vec<StrokeVertex> {
+    let half_widths = thicknesses.iter().map(|t| t / 2.0).collect::<Vec<_>>();
+    let mut vertices = vec![];
+
+    if centerline.is_empty() {
+        return vertices;
+    }
+    if centerline.len() == 1 {
+        // A single-point stroke is drawn as a square as wide as its thickness.
+        let c = centerline[0].coords;
+        let half_width = half_widths[0];
+        let dir = Vector2::y();
+        let n = normal(&dir);
+        push_quad(
+            &mut vertices,
+            StrokeVertex::new(c + n * half_width - dir * half_width, 0.0, 0.0),
+            StrokeVertex::new(c - n * half_width - dir * half_width, 0.0, 1.0),
+            StrokeVertex::new(c + n * half_width + dir * half_width, 1.0, 0.0),
+            StrokeVertex::new(c - n * half_width + dir * half_width, 1.0, 1.0),
+        );
+        return vertices;
+    }
+
+    let us = arc_length_parameters(centerline);
+    let dirs = centerline
+        .iter()
+        .zip(centerline.iter().skip(1))
+        .map(|(a, b)| (b - a).normalize())
+        .collect::<Vec<_>>();
+
+    // Offsets at the start and end of each segment.
+    let mut start_offsets = dirs
+        .iter()
+        .enumerate()
+        .map(|(i, d)| normal(d) * half_widths[i])
+        .collect::<Vec<_>>();
+    let mut end_offsets = dirs
+        .iter()
+        .enumerate()
+        .map(|(i, d)| normal(d) * half_widths[i + 1])
+        .collect::<Vec<_>>();
+    for i in 1..dirs.len() {
+        let (d0, d1) = (&dirs[i - 1], &dirs[i]);
+        let (n0, n1) = (normal(d0), normal(d1));
+        let p = centerline[i].coords;
+        let half_width = half_widths[i];
+
+        let miter = n0 + n1;
+        let cos = if miter.norm() > MIN_SEGMENT_LENGTH {
+            miter.normalize().dot(&n1)
+        } else {
+            0.0
+        };
+        let use_miter =
+            config.join == JoinType::Miter && cos > 0.0 && 1.0 / cos <= config.miter_limit;
+
+        if use_miter {
+            let offset = miter.normalize() * (half_width / cos);
+            end_offsets[i - 1] = offset;
+            start_offsets[i] = offset;
+        } else {
+            // Fill the gap on the outer side with a triangle.
+            let (sign, v) = if d0.perp(d1) > 0.0 {
+                (1.0, 0.0)
+            } else {
+                (-1.0, 1.0)
+            };
+            vertices.push(StrokeVertex::new(p, us[i], 0.5));
+            vertices.push(StrokeVertex::new(p + n0 * half_width * sign, us[i], v));
+            vertices.push(StrokeVertex::new(p + n1 * half_width * sign, us[i], v));
+        }
+    }
+
+    for (i, (p1, p2)) in centerline.iter().zip(centerline.iter().skip(1)).enumerate() {
+        push_quad(
+            &mut vertices,
+            StrokeVertex::new(p1.coords + start_offsets[i], us[i], 0.0),
+            StrokeVertex::new(p1.coords - start_offsets[i], us[i], 1.0),
+            StrokeVertex::new(p2.coords + end_offsets[i], us[i + 1], 0.0),
+            StrokeVertex::new(p2.coords - end_offsets[i], us[i + 1], 1.0),
+        );
+    }
+
+    let last = centerline.len() - 1;
+    push_cap(
+        &mut vertices,
+        centerline[0].coords,
+        -dirs[0],
+        normal(&dirs[0]),
+        0.0,
+        half_widths[0],
+        config,
+    );
+    push_cap(
+        &mut vertices,
+        centerline[last].coords,
+        dirs[last - 1],
+        normal(&dirs[last - 1]),
+        1.0,
+        half_widths[last],
+        config,
+    );
+
+    vertices
+}
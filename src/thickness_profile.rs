@@ -0,0 +1,113 @@
+use float_cmp::*;
+use lerp::Lerp;
+use rand::prelude::*;
+use rand_distr::Normal;
+
+use crate::config::BrushConfig;
+
+pub const TAPER_MAX: f32 = 0.5;
+const PRESSURE_MIN: f32 = 0.2;
+const PRESSURE_MAX: f32 = 1.5;
+
+// Thickness scale along a stroke.
+// u is the arc length parameter, 0.0 at the start of the stroke and 1.0 at its end.
+#[derive(Clone, Debug)]
+pub struct ThicknessProfile {
+    pub taper_in: f32,
+    pub taper_out: f32,
+    pub tip_width: f32,
+    pub pressure: Vec<f32>,
+}
+
+impl ThicknessProfile {
+    pub fn new(brush: &BrushConfig) -> Self {
+        let mut rng = thread_rng();
+        let taper_in = Self::sample_taper(brush.taper_in, brush.taper_variance, &mut rng);
+        let taper_out = Self::sample_taper(brush.taper_out, brush.taper_variance, &mut rng);
+        let pressure = (0..brush.pressure_points)
+            .map(|_| Self::sample_pressure(brush.pressure_variance, &mut rng))
+            .collect();
+        Self {
+            taper_in,
+            taper_out,
+            tip_width: brush.taper_tip_width,
+            pressure,
+        }
+    }
+
+    fn sample_taper(mean: f32, variance: f32, rng: &mut impl Rng) -> f32 {
+        if variance <= 0.0 {
+            return mean.clamp(0.0, TAPER_MAX);
+        }
+        let normal = Normal::new(mean, variance).unwrap();
+        normal.sample(rng).clamp(0.0, TAPER_MAX)
+    }
+
+    fn sample_pressure(variance: f32, rng: &mut impl Rng) -> f32 {
+        if variance <= 0.0 {
+            return 1.0;
+        }
+        let normal = Normal::new(1.0, variance).unwrap();
+        normal.sample(rng).clamp(PRESSURE_MIN, PRESSURE_MAX)
+    }
+
+    // Resample either a taper or a pressure point.
+    pub fn mutate(&mut self, brush: &BrushConfig) {
+        let mut rng = thread_rng();
+        match rng.gen_range(0, 3) {
+            0 => self.taper_in = Self::sample_taper(brush.taper_in, brush.taper_variance, &mut rng),
+            1 => {
+                self.taper_out = Self::sample_taper(brush.taper_out, brush.taper_variance, &mut rng)
+            }
+            _ => {
+                if !self.pressure.is_empty() {
+                    let i = rng.gen_range(0, self.pressure.len());
+                    self.pressure[i] = Self::sample_pressure(brush.pressure_variance, &mut rng);
+                }
+            }
+        }
+    }
+
+    pub fn eval(&self, u: f32) -> f32 {
+        let u = u.clamp(0.0, 1.0);
+
+        let pressure = match self.pressure.len() {
+            0 => 1.0,
+            1 => self.pressure[0],
+            n => {
+                let x = u * (n - 1) as f32;
+                let i = (x.floor() as usize).min(n - 2);
+                self.pressure[i].lerp(self.pressure[i + 1], x - i as f32)
+            }
+        };
+
+        let smoothstep = |t: f32| t * t * (3.0 - 2.0 * t);
+        let taper_in = if self.taper_in > 0.0 && u < self.taper_in {
+            self.tip_width.lerp(1.0, smoothstep(u / self.taper_in))
+        } else {
+            1.0
+        };
+        let taper_out = if self.taper_out > 0.0 && u > 1.0 - self.taper_out {
+            self.tip_width
+                .lerp(1.0, smoothstep((1.0 - u) / self.taper_out))
+        } else {
+            1.0
+        };
+
+        pressure * taper_in.min(taper_out)
+    }
+}
+
+impl PartialEq for ThicknessProfile {
+    fn eq(&self, other: &ThicknessProfile) -> bool {
+        approx_eq!(f32, self.taper_in, other.taper_in)
+            && approx_eq!(f32, self.taper_out, other.taper_out)
+            && approx_eq!(f32, self.tip_width, other.tip_width)
+            && self.pressure.len() == other.pressure.len()
+            && self
+                .pressure
+                .iter()
+                .zip(other.pressure.iter())
+                .all(|(&a, &b)| approx_eq!(f32, a, b))
+    }
+}
@@ -1,20 +1,235 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use anyhow::{anyhow, Context, Result};
 use c_str_macro::c_str;
 use gl;
-use image::GenericImageView;
-use na::{Matrix4, Point3, Rotation, Vector3};
+use image::{Rgb, RgbImage};
+use na::{Matrix4, Point2, Point3, Rotation, Vector2, Vector3};
 use nalgebra as na;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
 use sdl2;
 
+use crate::direction_field::DirectionField;
 use crate::render_gl;
 use crate::resources::Resources;
 use crate::triangle::Triangle;
 
-pub fn visualize_direction_map(input: &str, x: i32, y: i32) -> Result<()> {
-    let dir_map = image::open(input).unwrap();
-    let (width, height) = dir_map.dimensions();
+// Fixed seed for the LIC noise so the image is the same every time
+const LIC_NOISE_SEED: u64 = 0;
+
+// Glyph outline width. A dark outline around a light fill stays visible on any background.
+const GLYPH_OUTLINE: f32 = 1.0;
+const GLYPH_HALF_WIDTH: f32 = 0.75;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VisualizationStyle {
+    Lic,
+    Glyph,
+}
+
+impl FromStr for VisualizationStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lic" => Ok(VisualizationStyle::Lic),
+            "glyph" => Ok(VisualizationStyle::Glyph),
+            _ => Err(anyhow!("unknown visualization style: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub style: VisualizationStyle,
+    // Image to draw over, meant to be the color map
+    pub background: Option<PathBuf>,
+    // Number of glyphs across and down
+    pub grid: u32,
+    // Glyph length in pixels
+    pub glyph_size: f32,
+    // Length in pixels of the LIC streamline on each side
+    pub lic_length: u32,
+}
+
+// Trace a streamline both ways from p and average the noise along it. d and -d are the same
+// orientation, so flip the direction when it points against the previous step.
+fn line_integral(field: &DirectionField, noise: &[f32], p: Point2<f32>, length: u32) -> f32 {
+    let (w, h) = (field.width as i32, field.height as i32);
+    let index = |p: &Point2<f32>| {
+        let (x, y) = (p.x.round() as i32, p.y.round() as i32);
+        if 0 <= x && x < w && 0 <= y && y < h {
+            Some((y * w + x) as usize)
+        } else {
+            None
+        }
+    };
+
+    let start = index(&p).unwrap();
+    let mut sum = noise[start];
+    let mut count = 1.0;
+    for &sign in &[1.0, -1.0] {
+        let mut q = p;
+        let mut prev: Vector2<f32> = field.directions[start] * sign;
+        for _ in 0..length {
+            let mut d = match index(&q) {
+                Some(i) => field.directions[i],
+                None => break,
+            };
+            if d.dot(&prev) < 0.0 {
+                d = -d;
+            }
+            q += d;
+            prev = d;
+            match index(&q) {
+                Some(i) => {
+                    sum += noise[i];
+                    count += 1.0;
+                }
+                None => break,
+            }
+        }
+    }
+    sum / count
+}
+
+fn render_lic(field: &DirectionField, length: u32) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(LIC_NOISE_SEED);
+    let noise = (0..field.directions.len())
+        .map(|_| if rng.gen::<bool>() { 1.0 } else { 0.0 })
+        .collect::<Vec<f32>>();
+    let values = (0..field.directions.len())
+        .into_par_iter()
+        .map(|i| {
+            let p = Point2::new(
+                (i as u32 % field.width) as f32,
+                (i as u32 / field.width) as f32,
+            );
+            line_integral(field, &noise, p, length)
+        })
+        .collect::<Vec<_>>();
+
+    // Averaging lowers the contrast, so stretch to 0 to 1.
+    let min = values.iter().cloned().fold(f32::MAX, f32::min);
+    let max = values.iter().cloned().fold(f32::MIN, f32::max);
+    if max > min {
+        values.iter().map(|v| (v - min) / (max - min)).collect()
+    } else {
+        values
+    }
+}
+
+fn draw_segment(image: &mut RgbImage, a: Point2<f32>, b: Point2<f32>, radius: f32, color: Rgb<u8>) {
+    let (width, height) = image.dimensions();
+    let x0 = (a.x.min(b.x) - radius).floor().max(0.0) as u32;
+    let y0 = (a.y.min(b.y) - radius).floor().max(0.0) as u32;
+    let x1 = ((a.x.max(b.x) + radius).ceil() as u32).min(width - 1);
+    let y1 = ((a.y.max(b.y) + radius).ceil() as u32).min(height - 1);
+    let ab = b - a;
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let p = Point2::new(x as f32, y as f32);
+            let t = if ab.norm_squared() > 0.0 {
+                ((p - a).dot(&ab) / ab.norm_squared()).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            if na::distance(&p, &(a + ab * t)) <= radius {
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+// Save a direction map as a LIC or glyph image. Opens no window, so it works in CI.
+pub fn export_direction_map_visualization(
+    input: &Path,
+    output: &Path,
+    options: &ExportOptions,
+) -> Result<()> {
+    let field = DirectionField::load(input)?;
+    let (width, height) = (field.width, field.height);
+    let background = match &options.background {
+        Some(path) => {
+            let background = image::open(path)?.to_rgb();
+            if background.dimensions() != (width, height) {
+                return Err(anyhow!(
+                    "the background is {}x{} but the direction map is {}x{}",
+                    background.width(),
+                    background.height(),
+                    width,
+                    height
+                ));
+            }
+            Some(background)
+        }
+        None => None,
+    };
+
+    let image = match options.style {
+        VisualizationStyle::Lic => {
+            let lic = render_lic(&field, options.lic_length);
+            RgbImage::from_fn(width, height, |x, y| {
+                let v = lic[(y * width + x) as usize];
+                match &background {
+                    // Modulate the background color by the LIC intensity.
+                    Some(background) => {
+                        let c = background.get_pixel(x, y);
+                        let scale = 0.3 + 0.7 * v;
+                        Rgb([
+                            (c[0] as f32 * scale) as u8,
+                            (c[1] as f32 * scale) as u8,
+                            (c[2] as f32 * scale) as u8,
+                        ])
+                    }
+                    None => {
+                        let v = (v * 255.0) as u8;
+                        Rgb([v, v, v])
+                    }
+                }
+            })
+        }
+        VisualizationStyle::Glyph => {
+            let mut image = background.unwrap_or_else(|| RgbImage::new(width, height));
+            let grid = options.grid.max(1);
+            let (cell_w, cell_h) = (width as f32 / grid as f32, height as f32 / grid as f32);
+            let mut glyphs = vec![];
+            for gy in 0..grid {
+                for gx in 0..grid {
+                    let center =
+                        Point2::new((gx as f32 + 0.5) * cell_w, (gy as f32 + 0.5) * cell_h);
+                    let x = (center.x as u32).min(width - 1);
+                    let y = (center.y as u32).min(height - 1);
+                    let d = field.directions[(y * width + x) as usize];
+                    let half = d * (options.glyph_size / 2.0);
+                    glyphs.push((center - half, center + half));
+                }
+            }
+            for &(a, b) in &glyphs {
+                draw_segment(
+                    &mut image,
+                    a,
+                    b,
+                    GLYPH_HALF_WIDTH + GLYPH_OUTLINE,
+                    Rgb([0, 0, 0]),
+                );
+            }
+            for &(a, b) in &glyphs {
+                draw_segment(&mut image, a, b, GLYPH_HALF_WIDTH, Rgb([255, 255, 255]));
+            }
+            image
+        }
+    };
+    image.save(output)?;
+    Ok(())
+}
+
+pub fn visualize_direction_map(input: &Path, x: i32, y: i32, glyph_size: f32) -> Result<()> {
+    let dir_map = DirectionField::load(input)?;
+    let (width, height) = (dir_map.width, dir_map.height);
     let aspect = width as f64 / height as f64;
 
     let res =
@@ -75,17 +290,13 @@ pub fn visualize_direction_map(input: &str, x: i32, y: i32) -> Result<()> {
                 let x = wx * x as f32 + offset_x;
                 let y = hy * y as f32 + offset_y;
 
-                let scale = Matrix4::new_scaling(5.0);
+                // The triangle is 2 tall, so scale by half to make it glyph_size long.
+                let scale = Matrix4::new_scaling(glyph_size / 2.0);
 
-                let dir = dir_map.get_pixel(
-                    (x + (width as f32 / 2.0)).round() as u32,
-                    (-y + (height as f32 / 2.0)).round() as u32,
-                );
-                let dir = Vector3::new(
-                    (dir.0[0] as f32 / 255.0) * 2.0 - 1.0,
-                    (dir.0[1] as f32 / 255.0) * 2.0 - 1.0,
-                    0.0,
-                );
+                let px = ((x + (width as f32 / 2.0)).round() as u32).min(width - 1);
+                let py = ((-y + (height as f32 / 2.0)).round() as u32).min(height - 1);
+                let dir = dir_map.directions[(py * width + px) as usize];
+                let dir = Vector3::new(dir.x, dir.y, 0.0);
                 let rotation = Rotation::<_, na::U3>::rotation_between(&Vector3::y(), &dir)
                     .ok_or(anyhow!("calc rotation error"))?
                     .to_homogeneous();